@@ -0,0 +1,55 @@
+//! Compare the in-memory storage implementations under concurrent load.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rubbish::cas::{Hash, ShardedStorage, Storage, CAS};
+use std::sync::Arc;
+use std::thread;
+
+/// The number of objects each thread stores and touches per iteration
+const OPERATIONS: usize = 1000;
+
+/// Run a mixed workload of stores, touches, and retrieves from the given number of threads,
+/// each operating on its own objects, with a garbage-collection cycle in progress.
+fn workload<ST: CAS + Send + Sync + 'static>(storage: &Arc<ST>, threads: usize) {
+    storage.begin_gc().unwrap();
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let storage = storage.clone();
+            thread::spawn(move || {
+                let hashes: Vec<Hash> = (0..OPERATIONS)
+                    .map(|i| storage.store(format!("{}-{}", t, i).into_bytes()).unwrap())
+                    .collect();
+                for hash in &hashes {
+                    storage.touch(hash).unwrap();
+                    storage.retrieve(hash).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    storage.end_gc();
+}
+
+fn concurrent(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent");
+    for threads in [1, 4, 16].iter() {
+        group.bench_with_input(BenchmarkId::new("Storage", threads), threads, |b, &t| {
+            let storage = Arc::new(Storage::new());
+            b.iter(|| workload(&storage, t))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("ShardedStorage", threads),
+            threads,
+            |b, &t| {
+                let storage = Arc::new(ShardedStorage::new());
+                b.iter(|| workload(&storage, t))
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent);
+criterion_main!(benches);
@@ -0,0 +1,220 @@
+use super::hash::Hash;
+use super::traits::{Content, CAS};
+use async_trait::async_trait;
+use failure::Fallible;
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::task;
+
+/// Asynchronous Content Addressible Storage
+///
+/// This trait has the same semantics as `CAS`, but its methods are async, so that
+/// implementations which must wait for disk or network I/O do not block the Tokio runtime.
+///
+/// Since there is no asynchronous equivalent of `Drop`, there is no equivalent of `GarbageCycle`
+/// for this trait: callers must call `end_gc` exactly once for each call to `begin_gc`.
+///
+/// Types implementing both `CAS` and `AsyncCAS` have identically-named methods, so if both
+/// traits are in scope it is necessary to disambiguate calls, e.g., `AsyncCAS::store(&storage,
+/// value)`.
+#[async_trait]
+pub trait AsyncCAS: std::fmt::Debug + Send + Sync {
+    /// Store a value into the storage pool, returning its hash.
+    async fn store(&self, value: Content) -> Fallible<Hash>;
+
+    /// Retrieve a value by hash.
+    async fn retrieve(&self, hash: &Hash) -> Fallible<Content>;
+
+    /// Mark a value as part of the current garbage-collection generation.
+    async fn touch(&self, hash: &Hash) -> Fallible<()>;
+
+    /// Begin a garbage collection round.
+    async fn begin_gc(&self) -> Fallible<()>;
+
+    /// Complete a garbage collection round.
+    async fn end_gc(&self);
+
+    /// Complete a garbage collection round without collecting anything.
+    async fn abort_gc(&self);
+}
+
+/// Type AsyncAdapter adapts a `CAS` implementation to `AsyncCAS`.  Each operation runs on a
+/// thread where blocking is acceptable (via `tokio::task::spawn_blocking`), so even a `CAS`
+/// implementation that blocks for a long time will not block the Tokio runtime.
+#[derive(Debug)]
+pub struct AsyncAdapter<ST>(Arc<ST>)
+where
+    ST: CAS + Send + Sync + 'static;
+
+impl<ST> AsyncAdapter<ST>
+where
+    ST: CAS + Send + Sync + 'static,
+{
+    /// Create a new adapter around the given storage.
+    pub fn new(storage: ST) -> AsyncAdapter<ST> {
+        AsyncAdapter(Arc::new(storage))
+    }
+}
+
+#[async_trait]
+impl<ST> AsyncCAS for AsyncAdapter<ST>
+where
+    ST: CAS + Send + Sync + 'static,
+{
+    async fn store(&self, value: Content) -> Fallible<Hash> {
+        let storage = self.0.clone();
+        task::spawn_blocking(move || storage.store(value)).await?
+    }
+
+    async fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        let storage = self.0.clone();
+        let hash = hash.clone();
+        task::spawn_blocking(move || storage.retrieve(&hash)).await?
+    }
+
+    async fn touch(&self, hash: &Hash) -> Fallible<()> {
+        let storage = self.0.clone();
+        let hash = hash.clone();
+        task::spawn_blocking(move || storage.touch(&hash)).await?
+    }
+
+    async fn begin_gc(&self) -> Fallible<()> {
+        let storage = self.0.clone();
+        task::spawn_blocking(move || storage.begin_gc()).await?
+    }
+
+    async fn end_gc(&self) {
+        let storage = self.0.clone();
+        // a JoinError here means end_gc panicked, which has already been reported
+        let _ = task::spawn_blocking(move || storage.end_gc()).await;
+    }
+
+    async fn abort_gc(&self) {
+        let storage = self.0.clone();
+        let _ = task::spawn_blocking(move || storage.abort_gc()).await;
+    }
+}
+
+/// Type SyncAdapter adapts an `AsyncCAS` implementation to `CAS`, by blocking the calling thread
+/// until each operation is complete.  This allows asynchronous storage to be used with the
+/// synchronous `fs` module.
+///
+/// The adapter must be created within a Tokio runtime, and its methods must be called from a
+/// thread that is not running that runtime's tasks, such as one created with
+/// `tokio::task::spawn_blocking` or `std::thread::spawn`.
+#[derive(Debug)]
+pub struct SyncAdapter<ST>
+where
+    ST: AsyncCAS,
+{
+    storage: ST,
+    handle: Handle,
+}
+
+impl<ST> SyncAdapter<ST>
+where
+    ST: AsyncCAS,
+{
+    /// Create a new adapter around the given storage, using the current Tokio runtime.
+    pub fn new(storage: ST) -> SyncAdapter<ST> {
+        SyncAdapter {
+            storage,
+            handle: Handle::current(),
+        }
+    }
+}
+
+impl<ST> CAS for SyncAdapter<ST>
+where
+    ST: AsyncCAS,
+{
+    fn store(&self, value: Content) -> Fallible<Hash> {
+        self.handle.block_on(self.storage.store(value))
+    }
+
+    fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        self.handle.block_on(self.storage.retrieve(hash))
+    }
+
+    fn touch(&self, hash: &Hash) -> Fallible<()> {
+        self.handle.block_on(self.storage.touch(hash))
+    }
+
+    fn begin_gc(&self) -> Fallible<()> {
+        self.handle.block_on(self.storage.begin_gc())
+    }
+
+    fn end_gc(&self) {
+        self.handle.block_on(self.storage.end_gc())
+    }
+
+    fn abort_gc(&self) {
+        self.handle.block_on(self.storage.abort_gc())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::{CheckpointStorage, DiskStorage, ShardedStorage, Storage};
+    use crate::util::test::TempDir;
+
+    /// Exercise the basic operations of an AsyncCAS implementation.
+    async fn exercise<ST: AsyncCAS>(storage: ST) {
+        let hash1 = storage.store(b"abc".to_vec()).await.unwrap();
+        let hash2 = storage.store(b"def".to_vec()).await.unwrap();
+        assert_eq!(storage.retrieve(&hash1).await.unwrap(), b"abc".to_vec());
+        assert!(storage.retrieve(&Hash::from_hex("0123")).await.is_err());
+
+        storage.begin_gc().await.unwrap();
+        storage.touch(&hash1).await.unwrap();
+        storage.end_gc().await;
+
+        assert!(storage.retrieve(&hash1).await.is_ok());
+        assert!(storage.retrieve(&hash2).await.is_err());
+        assert!(storage.touch(&hash2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn storage() {
+        exercise(Storage::new()).await;
+    }
+
+    #[tokio::test]
+    async fn sharded_storage() {
+        exercise(ShardedStorage::new()).await;
+    }
+
+    #[tokio::test]
+    async fn disk_storage() {
+        let dir = TempDir::new();
+        exercise(DiskStorage::new(dir.path()).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn checkpoint_storage() {
+        let dir = TempDir::new();
+        exercise(CheckpointStorage::new(dir.path()).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn async_adapter() {
+        exercise(AsyncAdapter::new(Storage::new())).await;
+    }
+
+    #[tokio::test]
+    async fn sync_adapter_round_trip() {
+        // adapt a sync storage to async and back again
+        let storage = SyncAdapter::new(AsyncAdapter::new(Storage::new()));
+        task::spawn_blocking(move || {
+            let hash = storage.store(b"xyz".to_vec()).unwrap();
+            assert_eq!(storage.retrieve(&hash).unwrap(), b"xyz".to_vec());
+
+            storage.begin_gc().unwrap();
+            storage.end_gc();
+            assert!(storage.retrieve(&hash).is_err());
+        })
+        .await
+        .unwrap();
+    }
+}
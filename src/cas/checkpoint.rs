@@ -0,0 +1,746 @@
+use super::async_cas::AsyncCAS;
+use super::hash::{Algorithm, Hash};
+use super::pin::Pins;
+use super::prefix::resolve_prefix;
+use super::stats::{GcTimer, Stats};
+use super::traits::{Content, ObjectInfo, Objects, StoreAs, CAS};
+use async_trait::async_trait;
+use byteorder::{ByteOrder, NetworkEndian};
+use failure::{bail, err_msg, Fallible};
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio::task;
+
+/// Type CheckpointStorage provides an in-memory content-addressible storage pool which is
+/// checkpointed to disk, one file per garbage-collection generation.
+///
+/// Every `store` or `touch` of an object that is not already in the current generation appends
+/// that object to the current generation's file.  Since a garbage-collection cycle touches or
+/// stores every non-garbage object, once the cycle is complete the files for older generations
+/// contain nothing of value, and `end_gc` deletes them.  This provides compaction of the on-disk
+/// data as a side-effect of garbage collection.  Pinned objects are touched by `end_gc`, so they
+/// are carried forward in the same way.  Pins themselves are not persisted.
+///
+/// Each file is a sequence of records of the form
+///
+/// ```text
+/// hash length (u32) | hash | content length (u64) | content
+/// ```
+///
+/// with all integers in network byte order.  When the storage is opened, the files are replayed
+/// in generation order.  A truncated record at the end of a file (from an interrupted write) is
+/// discarded.
+pub struct CheckpointStorage(Arc<RwLock<Inner>>);
+
+struct Inner {
+    dir: PathBuf,
+    algorithm: Algorithm,
+    map: BTreeMap<Hash, (u64, Content)>,
+    garbage_generation: u64,
+    cur_generation: u64,
+
+    /// The oldest generation whose file has not yet been deleted.  This is older than
+    /// garbage_generation if cycles were aborted.
+    oldest_generation: u64,
+
+    /// The file for cur_generation, open for appending
+    cur_file: fs::File,
+
+    pins: Pins,
+    gc_timer: GcTimer,
+}
+
+impl fmt::Debug for Inner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Inner")
+            .field("dir", &self.dir)
+            .field("algorithm", &self.algorithm)
+            .field("map", &self.map)
+            .field("garbage_generation", &self.garbage_generation)
+            .field("cur_generation", &self.cur_generation)
+            .finish()
+    }
+}
+
+impl fmt::Debug for CheckpointStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CheckpointStorage")
+            .field("inner", &self.0.read())
+            .finish()
+    }
+}
+
+/// Get the path of the file for the given generation
+fn generation_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("gen-{:016}", generation))
+}
+
+/// Parse a generation file name, returning its generation.
+fn parse_generation(name: &str) -> Option<u64> {
+    name.strip_prefix("gen-").and_then(|n| n.parse().ok())
+}
+
+/// Open a generation file for appending, creating it if necessary.
+fn open_generation(dir: &Path, generation: u64) -> Fallible<fs::File> {
+    Ok(fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(generation_path(dir, generation))?)
+}
+
+/// Replay the records in a generation file into the map, truncating any partial record at the
+/// end of the file.
+fn replay_generation(
+    dir: &Path,
+    generation: u64,
+    map: &mut BTreeMap<Hash, (u64, Content)>,
+) -> Fallible<()> {
+    let path = generation_path(dir, generation);
+    let mut buf = vec![];
+    fs::File::open(&path)?.read_to_end(&mut buf)?;
+
+    let mut pos = 0;
+    while let Some((len, hash, content)) = parse_record(&buf[pos..]) {
+        map.insert(hash, (generation, content));
+        pos += len;
+    }
+
+    if pos < buf.len() {
+        warn!(
+            "discarding {} bytes of partial record at end of {:?}",
+            buf.len() - pos,
+            path
+        );
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(pos as u64)?;
+    }
+    Ok(())
+}
+
+/// Parse a single record from the beginning of buf, returning its total length, hash, and
+/// content; or None if buf does not begin with a complete record.
+fn parse_record(buf: &[u8]) -> Option<(usize, Hash, Content)> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let hash_len = NetworkEndian::read_u32(&buf[..4]) as usize;
+    let mut pos: usize = 4;
+    // a corrupt length may overflow, in which case the record cannot be complete either
+    if buf.len() < pos.checked_add(hash_len)?.checked_add(8)? {
+        return None;
+    }
+    let hash = Hash::from_bytes(buf[pos..pos + hash_len].to_vec());
+    pos += hash_len;
+    let content_len = NetworkEndian::read_u64(&buf[pos..pos + 8]) as usize;
+    pos += 8;
+    let end = pos.checked_add(content_len)?;
+    if buf.len() < end {
+        return None;
+    }
+    let content = buf[pos..end].to_vec();
+    Some((end, hash, content))
+}
+
+impl CheckpointStorage {
+    /// Open a storage pool checkpointed to the given directory, creating the directory if
+    /// necessary.  Any objects checkpointed in the directory are loaded into memory.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Fallible<CheckpointStorage> {
+        CheckpointStorage::with_algorithm(dir, Algorithm::default())
+    }
+
+    /// Open a storage pool as with `new`, hashing newly-stored content with the given algorithm.
+    /// Objects already checkpointed in the directory remain available, regardless of the
+    /// algorithm used to store them.
+    pub fn with_algorithm<P: AsRef<Path>>(
+        dir: P,
+        algorithm: Algorithm,
+    ) -> Fallible<CheckpointStorage> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut generations = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            match entry.file_name().to_str().and_then(parse_generation) {
+                Some(generation) => generations.push(generation),
+                None => warn!("ignoring unexpected file {:?}", entry.path()),
+            }
+        }
+        generations.sort();
+
+        let mut map = BTreeMap::new();
+        for generation in generations.iter() {
+            replay_generation(&dir, *generation, &mut map)?;
+        }
+
+        let cur_generation = generations.last().cloned().unwrap_or(1);
+        let garbage_generation = generations.first().cloned().unwrap_or(1) - 1;
+        let cur_file = open_generation(&dir, cur_generation)?;
+        debug!(
+            "opened {:?} with {} objects in generations {}..={}",
+            dir,
+            map.len(),
+            garbage_generation + 1,
+            cur_generation
+        );
+
+        Ok(CheckpointStorage(Arc::new(RwLock::new(Inner {
+            dir,
+            algorithm,
+            map,
+            garbage_generation,
+            cur_generation,
+            oldest_generation: garbage_generation + 1,
+            cur_file,
+            pins: Pins::default(),
+            gc_timer: GcTimer::default(),
+        }))))
+    }
+}
+
+impl Inner {
+    /// Append a record for the given object to the current generation's file.
+    fn append(&mut self, hash: &Hash, value: &Content) -> Fallible<()> {
+        let hash_bytes = hash.as_bytes();
+        let mut record = vec![0u8; 4 + hash_bytes.len() + 8 + value.len()];
+        NetworkEndian::write_u32(&mut record[..4], hash_bytes.len() as u32);
+        let mut pos = 4;
+        record[pos..pos + hash_bytes.len()].copy_from_slice(hash_bytes);
+        pos += hash_bytes.len();
+        NetworkEndian::write_u64(&mut record[pos..pos + 8], value.len() as u64);
+        pos += 8;
+        record[pos..].copy_from_slice(value);
+
+        // write the record in a single call, so that a crash leaves at most one partial record
+        self.cur_file.write_all(&record)?;
+        Ok(())
+    }
+
+    fn store(&mut self, value: Content) -> Fallible<Hash> {
+        let hash = self.algorithm.hash(&value);
+        self.store_as(&hash, value)?;
+        Ok(hash)
+    }
+
+    fn store_as(&mut self, hash: &Hash, value: Content) -> Fallible<()> {
+        let cur_generation = self.cur_generation;
+        debug!("store content with hash {:?}", hash);
+        match self.map.get(hash) {
+            Some((generation, _)) if *generation == cur_generation => {}
+            _ => {
+                self.append(hash, &value)?;
+                self.map.insert(hash.clone(), (cur_generation, value));
+            }
+        }
+        Ok(())
+    }
+
+    fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        debug!("retrieve content with hash {:?}", hash);
+        match self.map.get(hash) {
+            None => bail!("No object found"),
+            Some(tup) => Ok(tup.1.clone()),
+        }
+    }
+
+    fn touch(&mut self, hash: &Hash) -> Fallible<()> {
+        debug!("touch content with hash {:?}", hash);
+        let cur_generation = self.cur_generation;
+        match self.map.remove(hash) {
+            None => bail!("No object found"),
+            Some((generation, value)) => {
+                let res = if generation == cur_generation {
+                    Ok(())
+                } else {
+                    self.append(hash, &value)
+                };
+                // on failure, the object remains in its old generation
+                let generation = if res.is_ok() {
+                    cur_generation
+                } else {
+                    generation
+                };
+                self.map.insert(hash.clone(), (generation, value));
+                res
+            }
+        }
+    }
+
+    /// Finish a GC cycle without collecting anything.  Objects that were not touched are now in
+    /// the garbage generation, so they are collected, and their files deleted, when the next
+    /// cycle ends.
+    fn abort_gc(&mut self) {
+        self.garbage_generation += 1;
+        debug!("abort_gc: garbage_generation={}", self.garbage_generation);
+        self.gc_timer.abort();
+    }
+}
+
+impl StoreAs for CheckpointStorage {
+    fn store_as(&self, hash: &Hash, value: Content) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.store_as(hash, value)
+    }
+}
+
+impl CAS for CheckpointStorage {
+    fn store(&self, value: Content) -> Fallible<Hash> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.store(value)
+    }
+
+    fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.retrieve(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> Fallible<bool> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        Ok(inner.map.contains_key(hash))
+    }
+
+    fn store_many(&self, values: Vec<Content>) -> Fallible<Vec<Hash>> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        values.into_iter().map(|v| inner.store(v)).collect()
+    }
+
+    fn retrieve_many(&self, hashes: &[Hash]) -> Fallible<Vec<Content>> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        hashes.iter().map(|h| inner.retrieve(h)).collect()
+    }
+
+    fn resolve_prefix(&self, prefix: &str) -> Fallible<Hash> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        resolve_prefix(&inner.map, prefix)
+    }
+
+    fn objects(&self) -> Fallible<Objects> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        let objects: Vec<ObjectInfo> = inner
+            .map
+            .iter()
+            .map(|(hash, (generation, value))| ObjectInfo {
+                hash: hash.clone(),
+                size: value.len() as u64,
+                generation: *generation,
+            })
+            .collect();
+        Ok(Box::new(objects.into_iter()))
+    }
+
+    fn touch(&self, hash: &Hash) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.touch(hash)
+    }
+
+    fn touch_many(&self, hashes: &[Hash]) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        for hash in hashes {
+            inner.touch(hash)?;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> Fallible<Stats> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        let mut stats = Stats::tally(
+            inner.map.values().map(|(gen, v)| (*gen, v.len() as u64)),
+            inner.cur_generation,
+            inner.garbage_generation,
+        );
+        stats.last_gc = inner.gc_timer.last_gc();
+        Ok(stats)
+    }
+
+    fn pin(&self, hash: &Hash) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        if !inner.map.contains_key(hash) {
+            bail!("No object found");
+        }
+        inner.pins.pin(hash);
+        Ok(())
+    }
+
+    fn unpin(&self, hash: &Hash) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.pins.unpin(hash)
+    }
+
+    fn begin_gc(&self) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        let cur_generation = inner.cur_generation + 1;
+        inner.cur_file = open_generation(&inner.dir, cur_generation)?;
+        inner.cur_generation = cur_generation;
+        inner.gc_timer.begin();
+        debug!("begin_gc: cur_generation={}", inner.cur_generation);
+        Ok(())
+    }
+
+    fn abort_gc(&self) {
+        // see Storage::end_gc regarding lock failures
+        if let Ok(mut inner) = self.0.write() {
+            inner.abort_gc();
+        }
+    }
+
+    fn end_gc(&self) {
+        if let Ok(mut inner) = self.0.write() {
+            // carry pinned objects forward into the current generation's file
+            for hash in inner.pins.hashes() {
+                if let Err(e) = inner.touch(&hash) {
+                    warn!(
+                        "could not carry forward pinned object {:?}; not collecting: {}",
+                        hash, e
+                    );
+                    inner.abort_gc();
+                    return;
+                }
+            }
+
+            // everything that survives this cycle must be safely on disk before the old
+            // generation files are deleted
+            if let Err(e) = inner.cur_file.sync_all() {
+                warn!(
+                    "could not sync generation {}; not collecting: {}",
+                    inner.cur_generation, e
+                );
+                inner.abort_gc();
+                return;
+            }
+
+            inner.garbage_generation += 1;
+            debug!("end_gc: garbage_generation={}", inner.garbage_generation);
+            let garbage_generation = inner.garbage_generation;
+
+            let old_map = mem::take(&mut inner.map);
+            let (map, garbage): (BTreeMap<_, _>, BTreeMap<_, _>) = old_map
+                .into_iter()
+                .partition(|(_, v)| v.0 > garbage_generation);
+            inner.map = map;
+            let bytes = garbage.values().map(|v| v.1.len() as u64).sum();
+            inner.gc_timer.end(garbage.len(), bytes);
+
+            for generation in inner.oldest_generation..=garbage_generation {
+                let path = generation_path(&inner.dir, generation);
+                if let Err(e) = fs::remove_file(&path) {
+                    // the objects in this file will be loaded again when the storage is next
+                    // opened, and collected in a subsequent cycle
+                    warn!("could not remove {:?}: {}", path, e);
+                }
+            }
+            inner.oldest_generation = garbage_generation + 1;
+        } else {
+            // see Storage::end_gc
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncCAS for CheckpointStorage {
+    // appending to and syncing the generation file may block for a long time, so each operation
+    // runs on a thread where blocking is acceptable, as in AsyncAdapter
+    async fn store(&self, value: Content) -> Fallible<Hash> {
+        let storage = CheckpointStorage(self.0.clone());
+        task::spawn_blocking(move || CAS::store(&storage, value)).await?
+    }
+
+    async fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        let storage = CheckpointStorage(self.0.clone());
+        let hash = hash.clone();
+        task::spawn_blocking(move || CAS::retrieve(&storage, &hash)).await?
+    }
+
+    async fn touch(&self, hash: &Hash) -> Fallible<()> {
+        let storage = CheckpointStorage(self.0.clone());
+        let hash = hash.clone();
+        task::spawn_blocking(move || CAS::touch(&storage, &hash)).await?
+    }
+
+    async fn begin_gc(&self) -> Fallible<()> {
+        let storage = CheckpointStorage(self.0.clone());
+        task::spawn_blocking(move || CAS::begin_gc(&storage)).await?
+    }
+
+    async fn end_gc(&self) {
+        let storage = CheckpointStorage(self.0.clone());
+        // see AsyncAdapter::end_gc
+        let _ = task::spawn_blocking(move || CAS::end_gc(&storage)).await;
+    }
+
+    async fn abort_gc(&self) {
+        let storage = CheckpointStorage(self.0.clone());
+        let _ = task::spawn_blocking(move || CAS::abort_gc(&storage)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generation_path, open_generation, parse_record, CheckpointStorage};
+    use crate::cas::hash::Hash;
+    use crate::cas::traits::CAS;
+    use crate::util::test::{init_env_logger, TempDir};
+    use std::fs;
+
+    #[test]
+    fn simple_put_get_strings() {
+        init_env_logger();
+        let dir = TempDir::new();
+
+        let storage = CheckpointStorage::new(dir.path()).unwrap();
+
+        let hash1 = storage.store(b"one".to_vec()).unwrap();
+        let hash2 = storage.store(b"two".to_vec()).unwrap();
+        let badhash = Hash::from_hex("000000");
+
+        assert_eq!(storage.retrieve(&hash1).unwrap(), b"one".to_vec());
+        assert_eq!(storage.retrieve(&hash2).unwrap(), b"two".to_vec());
+        assert!(storage.retrieve(&badhash).is_err());
+    }
+
+    #[test]
+    fn batch_survives_reopen() {
+        let dir = TempDir::new();
+
+        let hashes = {
+            let storage = CheckpointStorage::new(dir.path()).unwrap();
+            let hashes = storage
+                .store_many(vec![b"a".to_vec(), b"b".to_vec()])
+                .unwrap();
+            storage.begin_gc().unwrap();
+            storage.touch_many(&hashes[1..]).unwrap();
+            storage.end_gc();
+            hashes
+        };
+
+        let storage = CheckpointStorage::new(dir.path()).unwrap();
+        assert!(!storage.contains(&hashes[0]).unwrap());
+        assert_eq!(
+            storage.retrieve_many(&hashes[1..]).unwrap(),
+            vec![b"b".to_vec()]
+        );
+    }
+
+    #[test]
+    fn touch_fails() {
+        let dir = TempDir::new();
+        let storage = CheckpointStorage::new(dir.path()).unwrap();
+
+        assert!(storage.touch(&Hash::from_hex("1234")).is_err());
+    }
+
+    #[test]
+    fn survives_reopen() {
+        let dir = TempDir::new();
+
+        let hash = {
+            let storage = CheckpointStorage::new(dir.path()).unwrap();
+            storage.store(b"xyz".to_vec()).unwrap();
+            storage.store(b"xyz".to_vec()).unwrap()
+        };
+
+        let storage = CheckpointStorage::new(dir.path()).unwrap();
+        assert_eq!(storage.retrieve(&hash).unwrap(), b"xyz".to_vec());
+
+        // storing twice in the same generation only writes one record
+        let len = fs::metadata(generation_path(dir.path(), 1)).unwrap().len();
+        assert_eq!(len, 4 + 32 + 8 + 3);
+    }
+
+    #[test]
+    fn gc_retires_generation_files() {
+        let dir = TempDir::new();
+
+        let (hash1, hash2, hash3) = {
+            let storage = CheckpointStorage::new(dir.path()).unwrap();
+            let hash1 = storage.store(b"abc".to_vec()).unwrap();
+            let hash2 = storage.store(b"def".to_vec()).unwrap();
+
+            storage.begin_gc().unwrap();
+            assert!(generation_path(dir.path(), 2).exists());
+            storage.touch(&hash1).unwrap();
+            let hash3 = storage.store(b"ghi".to_vec()).unwrap();
+            storage.end_gc();
+
+            assert!(storage.retrieve(&hash1).is_ok()); // touched
+            assert!(storage.retrieve(&hash2).is_err()); // not referenced
+            assert!(storage.retrieve(&hash3).is_ok()); // stored
+            assert_eq!(
+                storage.stats().unwrap().last_gc.unwrap().objects_reclaimed,
+                1
+            );
+            (hash1, hash2, hash3)
+        };
+
+        assert!(!generation_path(dir.path(), 1).exists());
+        assert!(generation_path(dir.path(), 2).exists());
+
+        let storage = CheckpointStorage::new(dir.path()).unwrap();
+        assert_eq!(storage.retrieve(&hash1).unwrap(), b"abc".to_vec());
+        assert!(storage.retrieve(&hash2).is_err());
+        assert_eq!(storage.retrieve(&hash3).unwrap(), b"ghi".to_vec());
+
+        // a subsequent cycle carries on from the loaded generation
+        storage.begin_gc().unwrap();
+        storage.touch(&hash3).unwrap();
+        storage.end_gc();
+        assert!(!generation_path(dir.path(), 2).exists());
+        assert!(storage.retrieve(&hash1).is_err());
+        assert!(storage.retrieve(&hash3).is_ok());
+    }
+
+    #[test]
+    fn aborted_gc() {
+        let dir = TempDir::new();
+
+        let (hash1, hash2) = {
+            let storage = CheckpointStorage::new(dir.path()).unwrap();
+            let hash1 = storage.store(b"abc".to_vec()).unwrap();
+            let hash2 = storage.store(b"def".to_vec()).unwrap();
+
+            // nothing is collected by an aborted cycle
+            storage.begin_gc().unwrap();
+            storage.abort_gc();
+            assert!(storage.retrieve(&hash2).is_ok());
+            assert!(generation_path(dir.path(), 1).exists());
+
+            // the next cycle collects garbage from both cycles
+            storage.begin_gc().unwrap();
+            storage.touch(&hash1).unwrap();
+            storage.end_gc();
+            assert!(storage.retrieve(&hash1).is_ok());
+            assert!(storage.retrieve(&hash2).is_err());
+            (hash1, hash2)
+        };
+
+        assert!(!generation_path(dir.path(), 1).exists());
+        assert!(!generation_path(dir.path(), 2).exists());
+        let storage = CheckpointStorage::new(dir.path()).unwrap();
+        assert!(storage.retrieve(&hash1).is_ok());
+        assert!(storage.retrieve(&hash2).is_err());
+    }
+
+    #[test]
+    fn pinned_objects_carried_forward() {
+        let dir = TempDir::new();
+
+        let hash = {
+            let storage = CheckpointStorage::new(dir.path()).unwrap();
+            let hash = storage.store(b"pinned".to_vec()).unwrap();
+            storage.pin(&hash).unwrap();
+
+            storage.begin_gc().unwrap();
+            storage.end_gc();
+            assert!(storage.retrieve(&hash).is_ok());
+            hash
+        };
+
+        // the object was rewritten into generation 2 before generation 1 was deleted
+        assert!(!generation_path(dir.path(), 1).exists());
+        let storage = CheckpointStorage::new(dir.path()).unwrap();
+        assert_eq!(storage.retrieve(&hash).unwrap(), b"pinned".to_vec());
+    }
+
+    #[test]
+    fn pin_carry_forward_fails() {
+        let dir = TempDir::new();
+        let storage = CheckpointStorage::new(dir.path()).unwrap();
+        let pinned = storage.store(b"pinned".to_vec()).unwrap();
+        storage.pin(&pinned).unwrap();
+        let garbage = storage.store(b"garbage".to_vec()).unwrap();
+
+        // make appending to the new generation's file fail
+        storage.begin_gc().unwrap();
+        storage.0.write().unwrap().cur_file =
+            fs::File::open(generation_path(dir.path(), 2)).unwrap();
+        storage.end_gc();
+
+        // nothing was collected, but the cycle ended
+        assert!(storage.contains(&pinned).unwrap());
+        assert!(storage.contains(&garbage).unwrap());
+        assert!(generation_path(dir.path(), 1).exists());
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.garbage_generation + 1, stats.cur_generation);
+        assert_eq!(stats.last_gc, None);
+
+        // once the file is writable again, the next cycle collects normally
+        storage.0.write().unwrap().cur_file = open_generation(dir.path(), 2).unwrap();
+        storage.begin_gc().unwrap();
+        storage.end_gc();
+        assert!(storage.contains(&pinned).unwrap());
+        assert!(!storage.contains(&garbage).unwrap());
+        assert!(!generation_path(dir.path(), 1).exists());
+        assert!(!generation_path(dir.path(), 2).exists());
+    }
+
+    #[test]
+    fn sync_fails() {
+        let dir = TempDir::new();
+        let storage = CheckpointStorage::new(dir.path()).unwrap();
+        let garbage = storage.store(b"garbage".to_vec()).unwrap();
+
+        // syncing a character device fails
+        storage.begin_gc().unwrap();
+        storage.0.write().unwrap().cur_file = fs::File::open("/dev/null").unwrap();
+        storage.end_gc();
+
+        // nothing was collected, but the cycle ended
+        assert!(storage.contains(&garbage).unwrap());
+        assert!(generation_path(dir.path(), 1).exists());
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.garbage_generation + 1, stats.cur_generation);
+        assert_eq!(stats.last_gc, None);
+
+        // the next cycle opens a new file and collects normally
+        storage.begin_gc().unwrap();
+        storage.end_gc();
+        assert!(!storage.contains(&garbage).unwrap());
+        assert!(!generation_path(dir.path(), 1).exists());
+        assert_eq!(
+            storage.stats().unwrap().last_gc.unwrap().objects_reclaimed,
+            1
+        );
+    }
+
+    #[test]
+    fn partial_record_discarded() {
+        let dir = TempDir::new();
+
+        let hash = {
+            let storage = CheckpointStorage::new(dir.path()).unwrap();
+            storage.store(b"complete".to_vec()).unwrap()
+        };
+
+        // simulate a crash partway through writing a second record
+        let path = generation_path(dir.path(), 1);
+        let mut data = fs::read(&path).unwrap();
+        let complete_len = data.len() as u64;
+        data.extend_from_slice(&[0, 0, 0, 32, 1, 2, 3]);
+        fs::write(&path, data).unwrap();
+
+        let storage = CheckpointStorage::new(dir.path()).unwrap();
+        assert_eq!(storage.retrieve(&hash).unwrap(), b"complete".to_vec());
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete_len);
+
+        // and new records are appended cleanly
+        let hash2 = storage.store(b"more".to_vec()).unwrap();
+        let storage = CheckpointStorage::new(dir.path()).unwrap();
+        assert_eq!(storage.retrieve(&hash2).unwrap(), b"more".to_vec());
+    }
+
+    #[test]
+    fn overflowing_length() {
+        // a record claiming u64::MAX bytes of content is incomplete, not a panic
+        let mut buf = vec![0, 0, 0, 1, 0xab];
+        buf.extend_from_slice(&[0xff; 8]);
+        buf.extend_from_slice(b"content");
+        assert!(parse_record(&buf).is_none());
+    }
+}
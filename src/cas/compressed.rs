@@ -0,0 +1,268 @@
+use super::hash::{Algorithm, Hash};
+use super::stats::Stats;
+use super::traits::{Content, Objects, RawCAS, StoreAs, CAS};
+use failure::{bail, Fallible};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+/// Values at least this large are compressed by default
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
+
+/// Body encodings, stored in the first byte of each object in the inner storage
+const ENCODING_RAW: u8 = 0;
+const ENCODING_DEFLATE: u8 = 1;
+
+/// Type CompressedStorage wraps another storage pool, transparently compressing values with
+/// DEFLATE before storing them there.
+///
+/// Hashes are calculated over the uncompressed value, so a value has the same hash whether or not
+/// it is stored in a CompressedStorage, and the encoded form is stored in the inner storage under
+/// that hash (see `RawCAS`).  Values smaller than the threshold, or that do not get smaller when
+/// compressed, are stored uncompressed.
+///
+/// Since the inner storage holds encoded values, sizes reported by `objects` and `stats` are the
+/// encoded sizes.  For the same reason, `scrub` should be run on the CompressedStorage rather
+/// than on the inner storage, whose objects do not match their hashes.  When used with
+/// `ReplicatedStorage`, the compression should be beneath the replication, as other nodes verify
+/// that the content they receive matches its hash.
+#[derive(Debug)]
+pub struct CompressedStorage<ST: RawCAS> {
+    inner: ST,
+    algorithm: Algorithm,
+    threshold: usize,
+}
+
+impl<ST: RawCAS> CompressedStorage<ST> {
+    /// Wrap the given storage, compressing values at least `DEFAULT_COMPRESSION_THRESHOLD` bytes
+    /// long and hashing them with the default algorithm.
+    pub fn new(inner: ST) -> CompressedStorage<ST> {
+        CompressedStorage::with_threshold(inner, DEFAULT_COMPRESSION_THRESHOLD)
+    }
+
+    /// Wrap the given storage, compressing values at least `threshold` bytes long.
+    pub fn with_threshold(inner: ST, threshold: usize) -> CompressedStorage<ST> {
+        CompressedStorage::with_algorithm(inner, threshold, Algorithm::default())
+    }
+
+    /// Wrap the given storage as with `with_threshold`, hashing newly-stored values with the given
+    /// algorithm.
+    pub fn with_algorithm(
+        inner: ST,
+        threshold: usize,
+        algorithm: Algorithm,
+    ) -> CompressedStorage<ST> {
+        CompressedStorage {
+            inner,
+            algorithm,
+            threshold,
+        }
+    }
+
+    /// Get the wrapped storage
+    pub fn inner(&self) -> &ST {
+        &self.inner
+    }
+
+    fn encode(&self, value: Content) -> Fallible<Content> {
+        if value.len() >= self.threshold {
+            let mut encoder = DeflateEncoder::new(vec![ENCODING_DEFLATE], Compression::default());
+            encoder.write_all(&value)?;
+            let compressed = encoder.finish()?;
+            if compressed.len() < value.len() {
+                return Ok(compressed);
+            }
+        }
+
+        let mut encoded = Vec::with_capacity(value.len() + 1);
+        encoded.push(ENCODING_RAW);
+        encoded.extend_from_slice(&value);
+        Ok(encoded)
+    }
+
+    fn decode(hash: &Hash, encoded: Content) -> Fallible<Content> {
+        match encoded.first() {
+            Some(&ENCODING_RAW) => Ok(encoded[1..].to_vec()),
+            Some(&ENCODING_DEFLATE) => {
+                let mut value = vec![];
+                DeflateDecoder::new(&encoded[1..]).read_to_end(&mut value)?;
+                Ok(value)
+            }
+            Some(encoding) => bail!("{:?} has unknown encoding {}", hash, encoding),
+            None => bail!("{:?} is empty", hash),
+        }
+    }
+}
+
+impl<ST: RawCAS> StoreAs for CompressedStorage<ST> {
+    fn store_as(&self, hash: &Hash, value: Content) -> Fallible<()> {
+        let encoded = self.encode(value)?;
+        self.inner.store_as(hash, encoded)
+    }
+}
+
+impl<ST: RawCAS> CAS for CompressedStorage<ST> {
+    fn store(&self, value: Content) -> Fallible<Hash> {
+        let hash = self.algorithm.hash(&value);
+        self.store_as(&hash, value)?;
+        Ok(hash)
+    }
+
+    fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        Self::decode(hash, self.inner.retrieve(hash)?)
+    }
+
+    fn contains(&self, hash: &Hash) -> Fallible<bool> {
+        self.inner.contains(hash)
+    }
+
+    fn retrieve_many(&self, hashes: &[Hash]) -> Fallible<Vec<Content>> {
+        hashes
+            .iter()
+            .zip(self.inner.retrieve_many(hashes)?)
+            .map(|(hash, encoded)| Self::decode(hash, encoded))
+            .collect()
+    }
+
+    fn resolve_prefix(&self, prefix: &str) -> Fallible<Hash> {
+        self.inner.resolve_prefix(prefix)
+    }
+
+    fn objects(&self) -> Fallible<Objects> {
+        self.inner.objects()
+    }
+
+    fn stats(&self) -> Fallible<Stats> {
+        self.inner.stats()
+    }
+
+    fn touch(&self, hash: &Hash) -> Fallible<()> {
+        self.inner.touch(hash)
+    }
+
+    fn touch_many(&self, hashes: &[Hash]) -> Fallible<()> {
+        self.inner.touch_many(hashes)
+    }
+
+    fn pin(&self, hash: &Hash) -> Fallible<()> {
+        self.inner.pin(hash)
+    }
+
+    fn unpin(&self, hash: &Hash) -> Fallible<()> {
+        self.inner.unpin(hash)
+    }
+
+    fn begin_gc(&self) -> Fallible<()> {
+        self.inner.begin_gc()
+    }
+
+    fn end_gc(&self) {
+        self.inner.end_gc()
+    }
+
+    fn abort_gc(&self) {
+        self.inner.abort_gc()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::{scrub, DiskStorage, Storage};
+    use crate::fs::{Commit, FileSystem, Tree};
+    use crate::util::test::TempDir;
+
+    #[test]
+    fn small_values_stored_raw() {
+        let storage = CompressedStorage::new(Storage::new());
+
+        let hash = storage.store(b"abc".to_vec()).unwrap();
+        assert_eq!(hash, Hash::for_bytes(&b"abc".to_vec()));
+        assert_eq!(storage.retrieve(&hash).unwrap(), b"abc".to_vec());
+        assert_eq!(
+            storage.inner().retrieve(&hash).unwrap(),
+            vec![ENCODING_RAW, b'a', b'b', b'c']
+        );
+    }
+
+    #[test]
+    fn large_values_compressed() {
+        let storage = CompressedStorage::new(Storage::new());
+
+        let value = b"abcdefgh".repeat(1000);
+        let hash = storage.store(value.clone()).unwrap();
+        assert_eq!(hash, Hash::for_bytes(&value));
+        assert_eq!(storage.retrieve(&hash).unwrap(), value);
+
+        let encoded = storage.inner().retrieve(&hash).unwrap();
+        assert_eq!(encoded[0], ENCODING_DEFLATE);
+        assert!(encoded.len() < value.len() / 10);
+        assert_eq!(
+            storage.retrieve_many(&[hash.clone(), hash]).unwrap(),
+            vec![value.clone(), value]
+        );
+    }
+
+    #[test]
+    fn bad_encoding() {
+        let storage = CompressedStorage::new(Storage::new());
+        let hash = Hash::for_bytes(&b"abc".to_vec());
+        storage.inner().store_as(&hash, vec![99, 1, 2]).unwrap();
+        assert!(storage.retrieve(&hash).is_err());
+    }
+
+    #[test]
+    fn gc() {
+        let storage = CompressedStorage::new(Storage::new());
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        let hash2 = storage.store(b"def".to_vec()).unwrap();
+
+        storage.begin_gc().unwrap();
+        storage.touch(&hash1).unwrap();
+        storage.end_gc();
+
+        assert!(storage.contains(&hash1).unwrap());
+        assert!(!storage.contains(&hash2).unwrap());
+    }
+
+    #[test]
+    fn filesystem_hashes_unchanged() {
+        let plain = FileSystem::new(Box::new(Storage::new()));
+        let compressed = FileSystem::new(Box::new(CompressedStorage::with_threshold(
+            Storage::new(),
+            0,
+        )));
+
+        let commit = |fs: &FileSystem| {
+            let tree = Tree::empty()
+                .write(fs, &["a", "b"], b"hello".repeat(100))
+                .unwrap();
+            let cmt = Commit::root(fs).unwrap().make_child(fs, &tree).unwrap();
+            cmt.hash(fs).unwrap().clone()
+        };
+        let hash = commit(&compressed);
+        assert_eq!(hash, commit(&plain));
+
+        let tree = Commit::for_hash(&hash).tree(&compressed).unwrap();
+        assert_eq!(
+            tree.read(&compressed, &["a", "b"]).unwrap(),
+            Some(b"hello".repeat(100))
+        );
+    }
+
+    #[test]
+    fn persistent() {
+        let dir = TempDir::new();
+        let value = b"persist".repeat(100);
+
+        let hash = {
+            let storage = CompressedStorage::new(DiskStorage::new(dir.path()).unwrap());
+            storage.store(value.clone()).unwrap()
+        };
+
+        let storage = CompressedStorage::new(DiskStorage::new(dir.path()).unwrap());
+        assert_eq!(storage.retrieve(&hash).unwrap(), value);
+        assert!(scrub(&storage).unwrap().is_clean());
+    }
+}
@@ -0,0 +1,482 @@
+use super::async_cas::AsyncCAS;
+use super::hash::{Algorithm, Hash};
+use super::pin::Pins;
+use super::prefix::resolve_prefix;
+use super::stats::{GcTimer, Stats};
+use super::traits::{Content, ObjectInfo, Objects, StoreAs, CAS};
+use async_trait::async_trait;
+use failure::{bail, err_msg, Fallible};
+use log::{debug, warn};
+use rustc_serialize::hex::FromHex;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio::task;
+
+/// Type DiskStorage provides a content-addressible storage pool that persists its content to a
+/// directory on disk, so that it survives a restart of the process.
+///
+/// Each object is stored in its own file under `objects/`, named by the hex representation of its
+/// hash and grouped into subdirectories by the first two hex digits of that hash.  Files are
+/// written to `tmp/` and then renamed into place, so a crash never leaves a partial object.
+///
+/// Garbage-collection generations and pins are tracked in memory.  When an existing directory is
+/// opened, all objects in it are considered part of the current generation, and none are pinned.
+pub struct DiskStorage(Arc<RwLock<Inner>>);
+
+#[derive(Debug)]
+struct Inner {
+    root: PathBuf,
+    algorithm: Algorithm,
+    /// The generation and size of each object
+    objects: BTreeMap<Hash, (u64, u64)>,
+    garbage_generation: u64,
+    cur_generation: u64,
+    pins: Pins,
+    gc_timer: GcTimer,
+}
+
+impl fmt::Debug for DiskStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiskStorage")
+            .field("inner", &self.0.read())
+            .finish()
+    }
+}
+
+impl DiskStorage {
+    /// Open a storage pool in the given directory, creating the directory if necessary.  Any
+    /// objects already present in the directory are available immediately.
+    pub fn new<P: AsRef<Path>>(root: P) -> Fallible<DiskStorage> {
+        DiskStorage::with_algorithm(root, Algorithm::default())
+    }
+
+    /// Open a storage pool as with `new`, hashing newly-stored content with the given algorithm.
+    /// Objects already present in the directory remain available, regardless of the algorithm
+    /// used to store them.
+    pub fn with_algorithm<P: AsRef<Path>>(root: P, algorithm: Algorithm) -> Fallible<DiskStorage> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(root.join("objects"))?;
+
+        // anything left in tmp/ is from an interrupted write
+        let tmp = root.join("tmp");
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir_all(&tmp)?;
+
+        let cur_generation = 1;
+        let mut objects = BTreeMap::new();
+        for subdir in fs::read_dir(root.join("objects"))? {
+            let subdir = subdir?;
+            if !subdir.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(subdir.path())? {
+                let entry = entry?;
+                let name = entry.file_name();
+                match name.to_str().map(|n| n.from_hex()) {
+                    Some(Ok(bytes)) => {
+                        let size = entry.metadata()?.len();
+                        objects.insert(Hash::from_bytes(bytes), (cur_generation, size));
+                    }
+                    _ => warn!("ignoring unexpected file {:?}", entry.path()),
+                }
+            }
+        }
+        debug!("opened {:?} with {} objects", root, objects.len());
+
+        Ok(DiskStorage(Arc::new(RwLock::new(Inner {
+            root,
+            algorithm,
+            objects,
+            garbage_generation: 0,
+            cur_generation,
+            pins: Pins::default(),
+            gc_timer: GcTimer::default(),
+        }))))
+    }
+}
+
+impl Inner {
+    /// Get the path at which the object with the given hash is stored
+    fn object_path(&self, hash: &Hash) -> PathBuf {
+        let hex = hash.to_hex();
+        let dir = if hex.len() >= 2 { &hex[..2] } else { "00" };
+        self.root.join("objects").join(dir).join(hex)
+    }
+
+    /// Write the given content to the object file for the given hash, atomically.
+    fn write_object(&self, hash: &Hash, value: &Content) -> Fallible<()> {
+        let path = self.object_path(hash);
+        fs::create_dir_all(path.parent().unwrap())?;
+
+        let tmp_path = self.root.join("tmp").join(hash.to_hex());
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(value)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn store(&mut self, value: Content) -> Fallible<Hash> {
+        let hash = self.algorithm.hash(&value);
+        self.store_as(&hash, value)?;
+        Ok(hash)
+    }
+
+    fn store_as(&mut self, hash: &Hash, value: Content) -> Fallible<()> {
+        debug!("store content with hash {:?}", hash);
+        if !self.objects.contains_key(hash) {
+            self.write_object(hash, &value)?;
+        }
+        self.objects
+            .insert(hash.clone(), (self.cur_generation, value.len() as u64));
+        Ok(())
+    }
+
+    fn touch(&mut self, hash: &Hash) -> Fallible<()> {
+        debug!("touch content with hash {:?}", hash);
+        match self.objects.get_mut(hash) {
+            None => bail!("No object found"),
+            Some((generation, _)) => {
+                *generation = self.cur_generation;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl StoreAs for DiskStorage {
+    fn store_as(&self, hash: &Hash, value: Content) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.store_as(hash, value)
+    }
+}
+
+impl CAS for DiskStorage {
+    fn store(&self, value: Content) -> Fallible<Hash> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.store(value)
+    }
+
+    fn store_many(&self, values: Vec<Content>) -> Fallible<Vec<Hash>> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        values.into_iter().map(|v| inner.store(v)).collect()
+    }
+
+    fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+
+        debug!("retrieve content with hash {:?}", hash);
+        if !inner.objects.contains_key(hash) {
+            bail!("No object found");
+        }
+        Ok(fs::read(inner.object_path(hash))?)
+    }
+
+    fn contains(&self, hash: &Hash) -> Fallible<bool> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        Ok(inner.objects.contains_key(hash))
+    }
+
+    fn resolve_prefix(&self, prefix: &str) -> Fallible<Hash> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        resolve_prefix(&inner.objects, prefix)
+    }
+
+    fn objects(&self) -> Fallible<Objects> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        let objects: Vec<ObjectInfo> = inner
+            .objects
+            .iter()
+            .map(|(hash, (generation, size))| ObjectInfo {
+                hash: hash.clone(),
+                size: *size,
+                generation: *generation,
+            })
+            .collect();
+        Ok(Box::new(objects.into_iter()))
+    }
+
+    fn touch(&self, hash: &Hash) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.touch(hash)
+    }
+
+    fn touch_many(&self, hashes: &[Hash]) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        for hash in hashes {
+            inner.touch(hash)?;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> Fallible<Stats> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        let mut stats = Stats::tally(
+            inner.objects.values().cloned(),
+            inner.cur_generation,
+            inner.garbage_generation,
+        );
+        stats.last_gc = inner.gc_timer.last_gc();
+        Ok(stats)
+    }
+
+    fn pin(&self, hash: &Hash) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        if !inner.objects.contains_key(hash) {
+            bail!("No object found");
+        }
+        inner.pins.pin(hash);
+        Ok(())
+    }
+
+    fn unpin(&self, hash: &Hash) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.pins.unpin(hash)
+    }
+
+    fn begin_gc(&self) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.gc_timer.begin();
+        inner.cur_generation += 1;
+        debug!("begin_gc: cur_generation={}", inner.cur_generation);
+        Ok(())
+    }
+
+    fn abort_gc(&self) {
+        // see end_gc regarding lock failures
+        if let Ok(mut inner) = self.0.write() {
+            // objects that were not touched are now in the garbage generation, so they are
+            // collected when the next cycle ends
+            inner.garbage_generation += 1;
+            debug!("abort_gc: garbage_generation={}", inner.garbage_generation);
+            inner.gc_timer.abort();
+        }
+    }
+
+    fn end_gc(&self) {
+        if let Ok(mut inner) = self.0.write() {
+            inner.garbage_generation += 1;
+            debug!("end_gc: garbage_generation={}", inner.garbage_generation);
+            let garbage_generation = inner.garbage_generation;
+
+            // see Storage::end_gc
+            for hash in inner.pins.hashes() {
+                let _ = inner.touch(&hash);
+            }
+
+            let garbage: Vec<Hash> = inner
+                .objects
+                .iter()
+                .filter(|(_, (gen, _))| *gen <= garbage_generation)
+                .map(|(hash, _)| hash.clone())
+                .collect();
+            let mut bytes = 0;
+            for hash in garbage.iter() {
+                if let Some((_, size)) = inner.objects.remove(hash) {
+                    bytes += size;
+                }
+                let path = inner.object_path(hash);
+                if let Err(e) = fs::remove_file(&path) {
+                    // the object is no longer reachable through this storage, so the only
+                    // consequence is wasted space until the next time the directory is opened
+                    warn!("could not remove {:?}: {}", path, e);
+                }
+            }
+            inner.gc_timer.end(garbage.len(), bytes);
+        } else {
+            // see Storage::end_gc
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncCAS for DiskStorage {
+    // file operations may block for a long time, so each runs on a thread where blocking is
+    // acceptable, as in AsyncAdapter
+    async fn store(&self, value: Content) -> Fallible<Hash> {
+        let storage = DiskStorage(self.0.clone());
+        task::spawn_blocking(move || CAS::store(&storage, value)).await?
+    }
+
+    async fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        let storage = DiskStorage(self.0.clone());
+        let hash = hash.clone();
+        task::spawn_blocking(move || CAS::retrieve(&storage, &hash)).await?
+    }
+
+    async fn touch(&self, hash: &Hash) -> Fallible<()> {
+        let storage = DiskStorage(self.0.clone());
+        let hash = hash.clone();
+        task::spawn_blocking(move || CAS::touch(&storage, &hash)).await?
+    }
+
+    async fn begin_gc(&self) -> Fallible<()> {
+        let storage = DiskStorage(self.0.clone());
+        task::spawn_blocking(move || CAS::begin_gc(&storage)).await?
+    }
+
+    async fn end_gc(&self) {
+        let storage = DiskStorage(self.0.clone());
+        // see AsyncAdapter::end_gc
+        let _ = task::spawn_blocking(move || CAS::end_gc(&storage)).await;
+    }
+
+    async fn abort_gc(&self) {
+        let storage = DiskStorage(self.0.clone());
+        let _ = task::spawn_blocking(move || CAS::abort_gc(&storage)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DiskStorage;
+    use crate::cas::hash::{Algorithm, Hash};
+    use crate::cas::traits::CAS;
+    use crate::fs::{Commit, FileSystem, Tree};
+    use crate::util::test::{init_env_logger, TempDir};
+
+    #[test]
+    fn simple_put_get_strings() {
+        init_env_logger();
+        let dir = TempDir::new();
+
+        let storage = DiskStorage::new(dir.path()).unwrap();
+
+        let hash1 = storage.store(b"one".to_vec()).unwrap();
+        let hash2 = storage.store(b"two".to_vec()).unwrap();
+        let badhash = Hash::from_hex("000000");
+
+        assert_eq!(storage.retrieve(&hash1).unwrap(), b"one".to_vec());
+        assert_eq!(storage.retrieve(&hash2).unwrap(), b"two".to_vec());
+        assert!(storage.retrieve(&badhash).is_err());
+    }
+
+    #[test]
+    fn put_twice() {
+        let dir = TempDir::new();
+        let storage = DiskStorage::new(dir.path()).unwrap();
+
+        let hash1 = storage.store(b"xyz".to_vec()).unwrap();
+        let hash2 = storage.store(b"xyz".to_vec()).unwrap();
+        assert_eq!(hash1, hash2);
+    }
+
+    #[test]
+    fn touch_fails() {
+        let dir = TempDir::new();
+        let storage = DiskStorage::new(dir.path()).unwrap();
+
+        assert!(storage.touch(&Hash::from_hex("1234")).is_err());
+    }
+
+    #[test]
+    fn survives_reopen() {
+        let dir = TempDir::new();
+
+        let hash = {
+            let storage = DiskStorage::new(dir.path()).unwrap();
+            storage.store(b"persistent".to_vec()).unwrap()
+        };
+
+        let storage = DiskStorage::new(dir.path()).unwrap();
+        assert_eq!(storage.retrieve(&hash).unwrap(), b"persistent".to_vec());
+        storage.touch(&hash).unwrap();
+        assert_eq!(storage.resolve_prefix(&hash.to_hex()[..6]).unwrap(), hash);
+        assert!(storage.contains(&hash).unwrap());
+        assert_eq!(storage.stats().unwrap().bytes, 10);
+        assert_eq!(
+            storage
+                .objects()
+                .unwrap()
+                .map(|o| o.hash)
+                .collect::<Vec<_>>(),
+            vec![hash.clone()]
+        );
+    }
+
+    #[test]
+    fn change_algorithm() {
+        let dir = TempDir::new();
+
+        let old_hash = {
+            let storage = DiskStorage::new(dir.path()).unwrap();
+            storage.store(b"old".to_vec()).unwrap()
+        };
+
+        // objects stored with SHA-256 are still available after switching to BLAKE3
+        let storage = DiskStorage::with_algorithm(dir.path(), Algorithm::Blake3).unwrap();
+        let new_hash = storage.store(b"new".to_vec()).unwrap();
+        assert_eq!(new_hash.algorithm(), Some(Algorithm::Blake3));
+        assert_eq!(storage.retrieve(&old_hash).unwrap(), b"old".to_vec());
+        assert_eq!(storage.retrieve(&new_hash).unwrap(), b"new".to_vec());
+    }
+
+    #[test]
+    fn gc() {
+        let dir = TempDir::new();
+        let storage = DiskStorage::new(dir.path()).unwrap();
+
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        let hash2 = storage.store(b"def".to_vec()).unwrap();
+        let hash3 = storage.store(b"ghi".to_vec()).unwrap();
+
+        storage.begin_gc().unwrap();
+        storage.touch(&hash1).unwrap();
+        storage.store(b"ghi".to_vec()).unwrap(); // hash3
+        storage.end_gc();
+
+        assert!(storage.retrieve(&hash1).is_ok()); // touched
+        assert!(storage.retrieve(&hash2).is_err()); // not referenced
+        assert!(storage.retrieve(&hash3).is_ok()); // stored
+
+        // and the garbage is gone from disk, too
+        let storage = DiskStorage::new(dir.path()).unwrap();
+        assert!(storage.retrieve(&hash1).is_ok());
+        assert!(storage.retrieve(&hash2).is_err());
+    }
+
+    #[test]
+    fn pin() {
+        let dir = TempDir::new();
+        let storage = DiskStorage::new(dir.path()).unwrap();
+
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        let hash2 = storage.store(b"def".to_vec()).unwrap();
+        storage.pin(&hash1).unwrap();
+        assert!(storage.pin(&Hash::from_hex("1234")).is_err());
+
+        storage.begin_gc().unwrap();
+        storage.end_gc();
+        assert!(storage.retrieve(&hash1).is_ok());
+        assert!(storage.retrieve(&hash2).is_err());
+
+        storage.unpin(&hash1).unwrap();
+        assert!(storage.unpin(&hash1).is_err());
+        storage.begin_gc().unwrap();
+        storage.end_gc();
+        assert!(storage.retrieve(&hash1).is_err());
+    }
+
+    #[test]
+    fn filesystem_survives_reopen() {
+        let dir = TempDir::new();
+
+        let hash = {
+            let fs = FileSystem::new(Box::new(DiskStorage::new(dir.path()).unwrap()));
+            let tree = Tree::empty().write(&fs, &["a", "b"], vec![1, 2]).unwrap();
+            let cmt = Commit::root(&fs).unwrap().make_child(&fs, &tree).unwrap();
+            cmt.hash(&fs).unwrap().clone()
+        };
+
+        let fs = FileSystem::new(Box::new(DiskStorage::new(dir.path()).unwrap()));
+        let cmt = Commit::for_hash(&hash);
+        let tree = cmt.tree(&fs).unwrap();
+        assert_eq!(tree.read(&fs, &["a", "b"]).unwrap(), Some(vec![1, 2]));
+    }
+}
@@ -0,0 +1,357 @@
+use super::hash::{Algorithm, Hash};
+use super::stats::Stats;
+use super::traits::{Content, Objects, RawCAS, StoreAs, CAS};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use failure::{bail, format_err, Fallible};
+use log::error;
+use rand::RngCore;
+use rustc_serialize::hex::{FromHex, ToHex};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// The length of an encryption key, in bytes
+const KEY_LEN: usize = 32;
+
+/// The length of an XChaCha20-Poly1305 nonce, in bytes
+const NONCE_LEN: usize = 24;
+
+/// Ciphertext formats, stored in the first byte of each object in the inner storage
+const FORMAT_XCHACHA20POLY1305: u8 = 1;
+
+/// An EncryptionKey is a 256-bit secret key for `EncryptedStorage`.
+///
+/// Keys are stored in files as 64 hex digits.  The key itself is never included in `Debug`
+/// output.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+impl EncryptionKey {
+    /// Generate a new, random key.
+    pub fn generate() -> EncryptionKey {
+        let mut key = [0u8; KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut key);
+        EncryptionKey(key)
+    }
+
+    /// Load a key from the given file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Fallible<EncryptionKey> {
+        let path = path.as_ref();
+        let hex = fs::read_to_string(path)?;
+        let bytes = match hex.trim().from_hex() {
+            Ok(bytes) => bytes,
+            Err(_) => bail!("{:?} does not contain a hex-encoded key", path),
+        };
+        if bytes.len() != KEY_LEN {
+            bail!("{:?} does not contain a {}-byte key", path, KEY_LEN);
+        }
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(&bytes);
+        Ok(EncryptionKey(key))
+    }
+
+    /// Write this key to a new file, readable only by its owner.  This fails if the file
+    /// already exists, so that an existing key is never overwritten.
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Fallible<()> {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        writeln!(file, "{}", self.0.to_hex())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// Type EncryptedStorage wraps another storage pool, encrypting each value with
+/// XChaCha20-Poly1305 before storing it there, so that the content of the inner storage (such as
+/// the files of a `DiskStorage`) is unreadable without the key.
+///
+/// Objects are addressed by the hash of their plaintext, so hashes and deduplication are the same
+/// as for unencrypted storage, and the encrypted form is stored in the inner storage under that
+/// hash (see `RawCAS`).  Each object is encrypted with a random nonce, and the hash is included
+/// as associated data, so ciphertext cannot be moved to another hash undetected.  Retrieving an
+/// object that has been tampered with, or that was encrypted with a different key, fails.
+///
+/// Note that hashes are not secret: they appear in object names and in `fs` trees, and reveal
+/// whether a guessed plaintext is present.  When used with `ReplicatedStorage`, the encryption
+/// should be beneath the replication, as other nodes verify that the content they receive matches
+/// its hash.
+pub struct EncryptedStorage<ST: RawCAS> {
+    inner: ST,
+    algorithm: Algorithm,
+    cipher: XChaCha20Poly1305,
+}
+
+impl<ST: RawCAS> fmt::Debug for EncryptedStorage<ST> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedStorage")
+            .field("inner", &self.inner)
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+
+impl<ST: RawCAS> EncryptedStorage<ST> {
+    /// Wrap the given storage, encrypting with the given key and hashing with the default
+    /// algorithm.
+    pub fn new(inner: ST, key: &EncryptionKey) -> EncryptedStorage<ST> {
+        EncryptedStorage::with_algorithm(inner, key, Algorithm::default())
+    }
+
+    /// Wrap the given storage as with `new`, hashing newly-stored values with the given algorithm.
+    pub fn with_algorithm(
+        inner: ST,
+        key: &EncryptionKey,
+        algorithm: Algorithm,
+    ) -> EncryptedStorage<ST> {
+        EncryptedStorage {
+            inner,
+            algorithm,
+            cipher: XChaCha20Poly1305::new(&Key::from(key.0)),
+        }
+    }
+
+    /// Get the wrapped storage
+    pub fn inner(&self) -> &ST {
+        &self.inner
+    }
+
+    fn encrypt(&self, hash: &Hash, value: &[u8]) -> Fallible<Content> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: value,
+            aad: hash.as_bytes(),
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&XNonce::from(nonce), payload)
+            .map_err(|_| format_err!("could not encrypt {:?}", hash))?;
+
+        let mut encrypted = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+        encrypted.push(FORMAT_XCHACHA20POLY1305);
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(encrypted)
+    }
+
+    fn decrypt(&self, hash: &Hash, encrypted: &[u8]) -> Fallible<Content> {
+        match encrypted.first() {
+            Some(&FORMAT_XCHACHA20POLY1305) if encrypted.len() > NONCE_LEN => {}
+            _ => bail!("{:?} is not a recognized encrypted object", hash),
+        }
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&encrypted[1..1 + NONCE_LEN]);
+        let payload = Payload {
+            msg: &encrypted[1 + NONCE_LEN..],
+            aad: hash.as_bytes(),
+        };
+        match self.cipher.decrypt(&XNonce::from(nonce), payload) {
+            Ok(value) => Ok(value),
+            Err(_) => {
+                error!(
+                    "{:?} failed authentication; it may have been tampered with",
+                    hash
+                );
+                bail!(
+                    "could not decrypt {:?}: modified, or encrypted with a different key",
+                    hash
+                )
+            }
+        }
+    }
+}
+
+impl<ST: RawCAS> StoreAs for EncryptedStorage<ST> {
+    fn store_as(&self, hash: &Hash, value: Content) -> Fallible<()> {
+        // encryption uses a random nonce, so re-storing an existing object would replace its
+        // ciphertext to no purpose; touch it instead
+        if self.inner.contains(hash)? {
+            return self.inner.touch(hash);
+        }
+        let encrypted = self.encrypt(hash, &value)?;
+        self.inner.store_as(hash, encrypted)
+    }
+}
+
+impl<ST: RawCAS> CAS for EncryptedStorage<ST> {
+    fn store(&self, value: Content) -> Fallible<Hash> {
+        let hash = self.algorithm.hash(&value);
+        self.store_as(&hash, value)?;
+        Ok(hash)
+    }
+
+    fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        self.decrypt(hash, &self.inner.retrieve(hash)?)
+    }
+
+    fn contains(&self, hash: &Hash) -> Fallible<bool> {
+        self.inner.contains(hash)
+    }
+
+    fn retrieve_many(&self, hashes: &[Hash]) -> Fallible<Vec<Content>> {
+        hashes
+            .iter()
+            .zip(self.inner.retrieve_many(hashes)?)
+            .map(|(hash, encrypted)| self.decrypt(hash, &encrypted))
+            .collect()
+    }
+
+    fn resolve_prefix(&self, prefix: &str) -> Fallible<Hash> {
+        self.inner.resolve_prefix(prefix)
+    }
+
+    fn objects(&self) -> Fallible<Objects> {
+        self.inner.objects()
+    }
+
+    fn stats(&self) -> Fallible<Stats> {
+        self.inner.stats()
+    }
+
+    fn touch(&self, hash: &Hash) -> Fallible<()> {
+        self.inner.touch(hash)
+    }
+
+    fn touch_many(&self, hashes: &[Hash]) -> Fallible<()> {
+        self.inner.touch_many(hashes)
+    }
+
+    fn pin(&self, hash: &Hash) -> Fallible<()> {
+        self.inner.pin(hash)
+    }
+
+    fn unpin(&self, hash: &Hash) -> Fallible<()> {
+        self.inner.unpin(hash)
+    }
+
+    fn begin_gc(&self) -> Fallible<()> {
+        self.inner.begin_gc()
+    }
+
+    fn end_gc(&self) {
+        self.inner.end_gc()
+    }
+
+    fn abort_gc(&self) {
+        self.inner.abort_gc()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::{DiskStorage, Storage};
+    use crate::util::test::TempDir;
+
+    #[test]
+    fn round_trip() {
+        let storage = EncryptedStorage::new(Storage::new(), &EncryptionKey::generate());
+
+        let hash = storage.store(b"secret".to_vec()).unwrap();
+        assert_eq!(hash, Hash::for_bytes(&b"secret".to_vec()));
+        assert_eq!(storage.retrieve(&hash).unwrap(), b"secret".to_vec());
+        assert_eq!(
+            storage.retrieve_many(&[hash.clone()]).unwrap(),
+            vec![b"secret".to_vec()]
+        );
+
+        // the inner storage does not contain the plaintext
+        let encrypted = storage.inner().retrieve(&hash).unwrap();
+        assert!(!encrypted.windows(6).any(|w| w == b"secret"));
+
+        // storing again does not change the ciphertext
+        storage.store(b"secret".to_vec()).unwrap();
+        assert_eq!(storage.inner().retrieve(&hash).unwrap(), encrypted);
+    }
+
+    #[test]
+    fn tampered() {
+        let storage = EncryptedStorage::new(Storage::new(), &EncryptionKey::generate());
+        let hash = storage.store(b"secret".to_vec()).unwrap();
+
+        let mut encrypted = storage.inner().retrieve(&hash).unwrap();
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        storage.inner().store_as(&hash, encrypted).unwrap();
+        assert!(storage.retrieve(&hash).is_err());
+    }
+
+    #[test]
+    fn moved() {
+        let key = EncryptionKey::generate();
+        let storage = EncryptedStorage::new(Storage::new(), &key);
+        let hash1 = storage.store(b"one".to_vec()).unwrap();
+        let hash2 = Hash::for_bytes(&b"two".to_vec());
+
+        // ciphertext stored under a different hash does not decrypt
+        let encrypted = storage.inner().retrieve(&hash1).unwrap();
+        storage.inner().store_as(&hash2, encrypted).unwrap();
+        assert!(storage.retrieve(&hash2).is_err());
+    }
+
+    #[test]
+    fn wrong_key() {
+        let dir = TempDir::new();
+        let hash = {
+            let storage = EncryptedStorage::new(
+                DiskStorage::new(dir.path()).unwrap(),
+                &EncryptionKey::generate(),
+            );
+            storage.store(b"secret".to_vec()).unwrap()
+        };
+
+        let storage = EncryptedStorage::new(
+            DiskStorage::new(dir.path()).unwrap(),
+            &EncryptionKey::generate(),
+        );
+        assert!(storage.contains(&hash).unwrap());
+        assert!(storage.retrieve(&hash).is_err());
+    }
+
+    #[test]
+    fn key_file() {
+        let dir = TempDir::new();
+        let path = dir.path().join("key");
+
+        let key = EncryptionKey::generate();
+        key.write_to_file(&path).unwrap();
+        assert!(key.write_to_file(&path).is_err());
+        assert_eq!(EncryptionKey::from_file(&path).unwrap().0, key.0);
+        assert_eq!(format!("{:?}", key), "EncryptionKey(..)");
+
+        fs::write(&path, "0123").unwrap();
+        assert!(EncryptionKey::from_file(&path).is_err());
+        fs::write(&path, "not hex").unwrap();
+        assert!(EncryptionKey::from_file(&path).is_err());
+    }
+
+    #[test]
+    fn persistent() {
+        let dir = TempDir::new();
+        let key_path = dir.path().join("key");
+        EncryptionKey::generate().write_to_file(&key_path).unwrap();
+        let objects = dir.path().join("objects");
+
+        let hash = {
+            let key = EncryptionKey::from_file(&key_path).unwrap();
+            let storage = EncryptedStorage::new(DiskStorage::new(&objects).unwrap(), &key);
+            storage.store(b"config".to_vec()).unwrap()
+        };
+
+        let key = EncryptionKey::from_file(&key_path).unwrap();
+        let storage = EncryptedStorage::new(DiskStorage::new(&objects).unwrap(), &key);
+        assert_eq!(storage.retrieve(&hash).unwrap(), b"config".to_vec());
+    }
+}
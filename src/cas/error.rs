@@ -1,7 +1,20 @@
+use super::hash::Hash;
 use failure::Fail;
 
 #[derive(Debug, Fail)]
 pub enum Error {
     #[fail(display = "Lock Error: {}", _0)]
     LockError(String),
+
+    #[fail(display = "Invalid hash {:?}", _0)]
+    InvalidHash(String),
+
+    #[fail(display = "Invalid hash prefix {:?}", _0)]
+    InvalidPrefix(String),
+
+    #[fail(display = "No object found with hash prefix {}", _0)]
+    PrefixNotFound(String),
+
+    #[fail(display = "Hash prefix {} is ambiguous; candidates: {:?}", _0, _1)]
+    AmbiguousPrefix(String, Vec<Hash>),
 }
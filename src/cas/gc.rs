@@ -2,6 +2,7 @@
 
 use super::traits::CAS;
 use failure::Fallible;
+use std::mem;
 
 /// Type GarbageCycle represents a garbage-collection cycle.  Between creation and destruction of
 /// an object of this type, touch or store every non-garbage object.  Any objects not touched
@@ -25,18 +26,25 @@ use failure::Fallible;
 /// // hash2 has been garbage-collected..
 /// assert!(storage.retrieve(&hash2).is_err());
 /// ```
-pub struct GarbageCycle<'a, ST: 'a + CAS> {
+pub struct GarbageCycle<'a, ST: 'a + CAS + ?Sized> {
     storage: &'a ST,
 }
 
-impl<'a, ST: 'a + CAS> GarbageCycle<'a, ST> {
+impl<'a, ST: 'a + CAS + ?Sized> GarbageCycle<'a, ST> {
     pub fn new(storage: &'a ST) -> Fallible<GarbageCycle<'a, ST>> {
         storage.begin_gc()?;
         Ok(GarbageCycle { storage: storage })
     }
+
+    /// Abandon this cycle without collecting anything, such as when it was not possible to touch
+    /// every non-garbage object.  Objects left untouched will be collected by a later cycle.
+    pub fn abandon(self) {
+        self.storage.abort_gc();
+        mem::forget(self)
+    }
 }
 
-impl<'a, ST: 'a + CAS> Drop for GarbageCycle<'a, ST> {
+impl<'a, ST: 'a + CAS + ?Sized> Drop for GarbageCycle<'a, ST> {
     fn drop(&mut self) {
         self.storage.end_gc()
     }
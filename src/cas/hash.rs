@@ -1,25 +1,106 @@
+use super::error::Error;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use rustc_serialize::hex::{FromHex, ToHex};
 use std::fmt;
+use std::str::FromStr;
+
+/// Multihash code for BLAKE3
+const BLAKE3_CODE: u8 = 0x1e;
+
+/// Length of a BLAKE3 digest
+const BLAKE3_LEN: u8 = 32;
+
+/// Length of a SHA-256 digest
+const SHA256_LEN: usize = 32;
+
+/// Algorithm identifies the hash function used to generate a Hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Algorithm {
+    /// SHA-256, the original (and default) algorithm
+    #[default]
+    Sha256,
+    /// BLAKE3, which is considerably faster than SHA-256
+    Blake3,
+}
+
+impl Algorithm {
+    /// Create a new hash for the given content, using this algorithm
+    pub fn hash(self, bytes: &[u8]) -> Hash {
+        match self {
+            Algorithm::Sha256 => {
+                let mut sha = Sha256::new();
+                sha.input(bytes);
+                let mut hash = Hash(vec![0; sha.output_bytes()]);
+                sha.result(&mut hash.0);
+                hash
+            }
+            Algorithm::Blake3 => {
+                let mut hash = vec![BLAKE3_CODE, BLAKE3_LEN];
+                hash.extend_from_slice(blake3::hash(bytes).as_bytes());
+                Hash(hash)
+            }
+        }
+    }
+}
 
 /// Type Hash represents the key under which content is stored.
+///
+/// Hashes are self-describing, in the style of multihash: all but SHA-256 hashes begin with a
+/// code identifying the algorithm and the length of the digest.  SHA-256 hashes are bare 32-byte
+/// digests, so that hashes generated before other algorithms were supported remain valid.
 #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, RustcDecodable, RustcEncodable)]
 pub struct Hash(Vec<u8>);
 
 impl Hash {
-    /// Create a new hash, given a hex representation.
+    /// Create a new hash, given a hex representation.  This panics if the representation is
+    /// invalid; use `str::parse` to parse untrusted input.
     pub fn from_hex(hex: &str) -> Hash {
         Hash(hex.from_hex().unwrap())
     }
 
-    /// Create a new hash for the given content
+    /// Create a new hash from its binary representation.
+    pub fn from_bytes(bytes: Vec<u8>) -> Hash {
+        Hash(bytes)
+    }
+
+    /// Create a new hash for the given content, using the default algorithm
     pub fn for_bytes(bytes: &Vec<u8>) -> Hash {
-        let mut sha = Sha256::new();
-        sha.input(bytes);
-        let mut hash = Hash(vec![0; sha.output_bytes()]);
-        sha.result(&mut hash.0);
-        return hash;
+        Algorithm::default().hash(bytes)
+    }
+
+    /// Get the algorithm that generated this hash, or None if it is not a valid hash.
+    pub fn algorithm(&self) -> Option<Algorithm> {
+        match &self.0[..] {
+            [BLAKE3_CODE, BLAKE3_LEN, digest @ ..] if digest.len() == BLAKE3_LEN as usize => {
+                Some(Algorithm::Blake3)
+            }
+            digest if digest.len() == SHA256_LEN => Some(Algorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Determine whether this is the hash of the given content, using the algorithm that
+    /// generated this hash.
+    pub fn verify(&self, bytes: &[u8]) -> bool {
+        match self.algorithm() {
+            Some(algorithm) => algorithm.hash(bytes) == *self,
+            None => false,
+        }
+    }
+
+    /// Get the digest portion of this hash, without any algorithm code.  For an invalid hash, this
+    /// is the entire binary representation.
+    pub fn digest(&self) -> &[u8] {
+        match self.algorithm() {
+            Some(Algorithm::Blake3) => &self.0[2..],
+            _ => &self.0,
+        }
+    }
+
+    /// Get the binary representation of this hash.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
     }
 
     // Get the hex representation of this hash.
@@ -28,6 +109,18 @@ impl Hash {
     }
 }
 
+impl FromStr for Hash {
+    type Err = Error;
+
+    /// Parse a hash from its hex representation.
+    fn from_str(hex: &str) -> Result<Hash, Error> {
+        match hex.from_hex() {
+            Ok(bytes) if !bytes.is_empty() => Ok(Hash(bytes)),
+            _ => Err(Error::InvalidHash(hex.to_string())),
+        }
+    }
+}
+
 impl fmt::Display for Hash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.to_hex())
@@ -42,7 +135,7 @@ impl fmt::Debug for Hash {
 
 #[cfg(test)]
 mod tests {
-    use super::Hash;
+    use super::{Algorithm, Hash};
 
     #[test]
     fn test_to_hex() {
@@ -56,6 +149,15 @@ mod tests {
         assert_eq!(hash.0, vec![0u8, 17, 34, 51, 68]);
     }
 
+    #[test]
+    fn test_parse() {
+        let hash: Hash = "0011223344".parse().unwrap();
+        assert_eq!(hash.0, vec![0u8, 17, 34, 51, 68]);
+        assert!("001122334".parse::<Hash>().is_err());
+        assert!("not hex!".parse::<Hash>().is_err());
+        assert!("".parse::<Hash>().is_err());
+    }
+
     #[test]
     fn hash_bytes() {
         let hash = Hash::for_bytes(&vec![1u8, 2, 3, 4]);
@@ -64,4 +166,41 @@ mod tests {
             "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a"
         );
     }
+
+    #[test]
+    fn hash_bytes_blake3() {
+        let hash = Algorithm::Blake3.hash(&[1u8, 2, 3, 4]);
+        assert_eq!(
+            hash.to_hex(),
+            "1e20\
+             63781d171425a36312fa058d8712d5d05135a991ec20351ce9d65cdb19a05432"
+        );
+    }
+
+    #[test]
+    fn algorithm() {
+        let sha = Hash::for_bytes(&vec![1u8, 2, 3, 4]);
+        assert_eq!(sha.algorithm(), Some(Algorithm::Sha256));
+        let blake = Algorithm::Blake3.hash(&[1u8, 2, 3, 4]);
+        assert_eq!(blake.algorithm(), Some(Algorithm::Blake3));
+        assert_eq!(Hash::from_hex("0123").algorithm(), None);
+    }
+
+    #[test]
+    fn digest() {
+        let sha = Hash::for_bytes(&vec![1u8, 2, 3, 4]);
+        assert_eq!(sha.digest(), sha.as_bytes());
+        let blake = Algorithm::Blake3.hash(&[1u8, 2, 3, 4]);
+        assert_eq!(blake.digest(), &blake.as_bytes()[2..]);
+        assert_eq!(Hash::from_hex("0123").digest(), &[0x01, 0x23]);
+    }
+
+    #[test]
+    fn verify() {
+        let data = vec![5u8, 6, 7];
+        assert!(Hash::for_bytes(&data).verify(&data));
+        assert!(Algorithm::Blake3.hash(&data).verify(&data));
+        assert!(!Algorithm::Blake3.hash(&data).verify(&[5u8, 6]));
+        assert!(!Hash::from_hex("0123").verify(&data));
+    }
 }
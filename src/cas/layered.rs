@@ -0,0 +1,799 @@
+use super::error::Error;
+use super::hash::Hash;
+use super::prefix::unique_match;
+use super::traits::{Content, ObjectInfo, Objects, CAS};
+use failure::{bail, err_msg, Fallible};
+use log::{debug, warn};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+
+/// WritePolicy determines when `LayeredStorage` writes newly-stored objects to its lower layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Store every object in every layer immediately
+    WriteThrough,
+
+    /// Store objects only in the first layer, writing them to the lower layers on `flush` or at
+    /// the end of a garbage-collection cycle.  Unwritten objects are pinned in the first layer,
+    /// so it must support `pin` and `unpin`.
+    WriteBack,
+}
+
+/// A layer of a LayeredStorage
+pub type Layer = Box<dyn CAS + Send + Sync>;
+
+/// Type LayeredStorage stacks several storage pools, such as a small in-memory `Storage` in front
+/// of a `DiskStorage`, in front of a `ReplicatedStorage` that can fetch objects from other nodes.
+///
+/// Reads consult the layers in order, and an object found in a lower layer is promoted by storing
+/// it in all of the layers above.  Writes are governed by the `WritePolicy`.
+///
+/// Each layer has its own garbage-collection generations, and `begin_gc` and `end_gc` are
+/// forwarded to every layer.  Touching an object touches it in every layer that contains it, so
+/// each layer retains the objects that are still in use.  In write-back mode, `end_gc` first ends
+/// the cycle for the first layer, then writes the surviving unwritten objects to the lower layers
+/// before ending their cycles.
+///
+/// In write-back mode, objects not yet written to the lower layers are pinned in the first layer,
+/// so that a first layer with a byte budget does not evict them before they are written.
+pub struct LayeredStorage {
+    layers: Vec<Layer>,
+    policy: WritePolicy,
+    dirty: Mutex<Dirty>,
+}
+
+/// Objects stored in the first layer but not yet written to the lower layers (write-back only)
+#[derive(Debug, Default)]
+struct Dirty {
+    /// The unwritten objects, each pinned once in the first layer
+    hashes: BTreeSet<Hash>,
+
+    /// The unwritten objects stored or touched in the current garbage-collection cycle, if one is
+    /// in progress
+    live: Option<BTreeSet<Hash>>,
+
+    /// The number of times the caller has pinned each object in the first layer, so that an
+    /// `unpin` by the caller cannot remove the pin held on an unwritten object
+    caller_pins: HashMap<Hash, usize>,
+}
+
+impl fmt::Debug for LayeredStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LayeredStorage")
+            .field("layers", &self.layers)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl LayeredStorage {
+    /// Create a new storage pool over the given layers, ordered from first to last.
+    pub fn new(layers: Vec<Layer>, policy: WritePolicy) -> Fallible<LayeredStorage> {
+        if layers.is_empty() {
+            bail!("LayeredStorage requires at least one layer");
+        }
+        Ok(LayeredStorage {
+            layers,
+            policy,
+            dirty: Mutex::new(Dirty::default()),
+        })
+    }
+
+    /// Get the layers, such as to examine their statistics
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// Write any objects not yet written to the lower layers.  This is only necessary in
+    /// write-back mode.
+    pub fn flush(&self) -> Fallible<()> {
+        let mut dirty = self.dirty.lock().map_err(|_| err_msg("Lock Poisoned"))?;
+        self.flush_dirty(&mut dirty)
+    }
+
+    /// Write the unwritten objects to the lower layers, with the dirty lock held
+    fn flush_dirty(&self, dirty: &mut Dirty) -> Fallible<()> {
+        while let Some(hash) = dirty.hashes.iter().next().cloned() {
+            // objects collected from the first layer were garbage, so need not be written
+            if self.layers[0].contains(&hash)? {
+                let value = self.layers[0].retrieve(&hash)?;
+                self.store_in(&self.layers[1..], &hash, value)?;
+                dirty.hashes.remove(&hash);
+                // the object is written, so a failure here only leaves it pinned
+                if let Err(e) = self.layers[0].unpin(&hash) {
+                    warn!("could not unpin written object {:?}: {}", hash, e);
+                }
+            } else {
+                debug!("not flushing collected object {:?}", hash);
+                dirty.hashes.remove(&hash);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unpin the unwritten objects that were neither stored nor touched in the garbage-collection
+    /// cycle now ending, so that the first layer can collect them.  Returns those objects.
+    fn unpin_garbage(&self, dirty: &mut Dirty) -> Vec<Hash> {
+        let live = match dirty.live.take() {
+            Some(live) => live,
+            None => return vec![],
+        };
+        let mut garbage = vec![];
+        for hash in dirty.hashes.iter() {
+            if live.contains(hash) {
+                continue;
+            }
+            match self.layers[0].unpin(hash) {
+                Ok(()) => garbage.push(hash.clone()),
+                Err(e) => warn!("could not unpin {:?}: {}", hash, e),
+            }
+        }
+        garbage
+    }
+
+    /// Remove a caller's pin from the first layer, refusing to remove the pin held on an unwritten
+    /// object
+    fn unpin_first(&self, dirty: &mut Dirty, hash: &Hash) -> Fallible<()> {
+        let pins = dirty.caller_pins.get(hash).cloned().unwrap_or(0);
+        if pins == 0 && dirty.hashes.contains(hash) {
+            bail!("{:?} is not pinned", hash);
+        }
+        self.layers[0].unpin(hash)?;
+        match pins {
+            0 => {}
+            1 => {
+                dirty.caller_pins.remove(hash);
+            }
+            _ => {
+                dirty.caller_pins.insert(hash.clone(), pins - 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Store a value in each of the given layers
+    fn store_in(&self, layers: &[Layer], hash: &Hash, value: Content) -> Fallible<()> {
+        for layer in layers {
+            let stored = layer.store(value.clone())?;
+            if stored != *hash {
+                // this occurs if the layers use different hash algorithms, in which case the
+                // object is not accessible via this hash in this layer
+                warn!("{:?} stored as {:?} in {:?}", hash, stored, layer);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CAS for LayeredStorage {
+    fn store(&self, value: Content) -> Fallible<Hash> {
+        match self.policy {
+            WritePolicy::WriteThrough => {
+                let hash = self.layers[0].store(value.clone())?;
+                self.store_in(&self.layers[1..], &hash, value)?;
+                Ok(hash)
+            }
+            WritePolicy::WriteBack => {
+                if self.layers.len() == 1 {
+                    return self.layers[0].store(value);
+                }
+                // hold the lock while storing, so that end_gc cannot unpin the object before
+                // it is recorded
+                let mut dirty = self.dirty.lock().map_err(|_| err_msg("Lock Poisoned"))?;
+                let hash = self.layers[0].store(value)?;
+                if dirty.hashes.insert(hash.clone()) {
+                    // the newly-stored object is never evicted, so it is present to be pinned
+                    if let Err(e) = self.layers[0].pin(&hash) {
+                        dirty.hashes.remove(&hash);
+                        return Err(e);
+                    }
+                }
+                if let Some(live) = dirty.live.as_mut() {
+                    live.insert(hash.clone());
+                }
+                Ok(hash)
+            }
+        }
+    }
+
+    fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        let mut last_err = None;
+        for (i, layer) in self.layers.iter().enumerate() {
+            match layer.retrieve(hash) {
+                Ok(value) => {
+                    if i > 0 {
+                        debug!("promoting {:?} from layer {}", hash, i);
+                        if let Err(e) = self.store_in(&self.layers[..i], hash, value.clone()) {
+                            // the value is still valid, so this is not a failure to retrieve it
+                            warn!("could not promote {:?} from layer {}: {}", hash, i, e);
+                        }
+                    }
+                    return Ok(value);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    fn contains(&self, hash: &Hash) -> Fallible<bool> {
+        for layer in self.layers.iter() {
+            if layer.contains(hash)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn resolve_prefix(&self, prefix: &str) -> Fallible<Hash> {
+        // different layers may contain different objects matching the prefix, so gather the
+        // candidates from all of them
+        let mut candidates = BTreeSet::new();
+        let mut other_err = None;
+        for layer in self.layers.iter() {
+            match layer.resolve_prefix(prefix) {
+                Ok(hash) => {
+                    candidates.insert(hash);
+                }
+                Err(e) => match e.downcast::<Error>() {
+                    Ok(Error::AmbiguousPrefix(_, hashes)) => candidates.extend(hashes),
+                    Ok(Error::PrefixNotFound(_)) => {}
+                    Ok(e) => other_err = Some(e.into()),
+                    Err(e) => other_err = Some(e),
+                },
+            }
+        }
+        if candidates.is_empty() {
+            if let Some(e) = other_err {
+                return Err(e);
+            }
+        }
+        unique_match(prefix.to_lowercase(), candidates.into_iter().collect())
+    }
+
+    fn objects(&self) -> Fallible<Objects> {
+        // list each object once, with its generation in the first layer containing it
+        let mut seen = HashSet::new();
+        let mut objects: Vec<ObjectInfo> = vec![];
+        for layer in self.layers.iter() {
+            for object in layer.objects()? {
+                if seen.insert(object.hash.clone()) {
+                    objects.push(object);
+                }
+            }
+        }
+        Ok(Box::new(objects.into_iter()))
+    }
+
+    fn touch(&self, hash: &Hash) -> Fallible<()> {
+        {
+            let mut dirty = self.dirty.lock().map_err(|_| err_msg("Lock Poisoned"))?;
+            let Dirty { hashes, live, .. } = &mut *dirty;
+            if let Some(live) = live.as_mut() {
+                if hashes.contains(hash) {
+                    live.insert(hash.clone());
+                }
+            }
+        }
+
+        let mut found = false;
+        for layer in self.layers.iter() {
+            if layer.contains(hash)? {
+                layer.touch(hash)?;
+                found = true;
+            }
+        }
+        if !found {
+            // retrieving the object fetches it if any layer can do so, and promotes it into the
+            // layers above, which marks it as part of their current generation
+            self.retrieve(hash)?;
+        }
+        Ok(())
+    }
+
+    fn pin(&self, hash: &Hash) -> Fallible<()> {
+        let mut dirty = self.dirty.lock().map_err(|_| err_msg("Lock Poisoned"))?;
+        let mut found = false;
+        for (i, layer) in self.layers.iter().enumerate() {
+            if layer.contains(hash)? {
+                layer.pin(hash)?;
+                if i == 0 {
+                    *dirty.caller_pins.entry(hash.clone()).or_insert(0) += 1;
+                }
+                found = true;
+            }
+        }
+        if !found {
+            bail!("No object found");
+        }
+        Ok(())
+    }
+
+    fn unpin(&self, hash: &Hash) -> Fallible<()> {
+        // the object may have been pinned in only some layers, or promoted since it was pinned
+        let mut dirty = self.dirty.lock().map_err(|_| err_msg("Lock Poisoned"))?;
+        let mut last_err = None;
+        let mut unpinned = false;
+        for (i, layer) in self.layers.iter().enumerate() {
+            let res = if i == 0 {
+                self.unpin_first(&mut dirty, hash)
+            } else {
+                layer.unpin(hash)
+            };
+            match res {
+                Ok(()) => unpinned = true,
+                Err(e) => last_err = Some(e),
+            }
+        }
+        match (unpinned, last_err) {
+            (false, Some(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn begin_gc(&self) -> Fallible<()> {
+        let mut dirty = self.dirty.lock().map_err(|_| err_msg("Lock Poisoned"))?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Err(e) = layer.begin_gc() {
+                // ending the cycles already begun would collect everything in those layers, so
+                // abort them instead
+                warn!("could not begin GC in layer {}: {}", i, e);
+                for layer in self.layers[..i].iter() {
+                    layer.abort_gc();
+                }
+                return Err(e);
+            }
+        }
+        dirty.live = Some(BTreeSet::new());
+        Ok(())
+    }
+
+    fn end_gc(&self) {
+        match self.dirty.lock() {
+            Ok(mut dirty) => {
+                let garbage = self.unpin_garbage(&mut dirty);
+                self.layers[0].end_gc();
+                for hash in garbage {
+                    // an object that survived collection, such as one pinned by the caller, is
+                    // still unwritten, so it must be pinned again
+                    if !self.layers[0].contains(&hash).unwrap_or(false)
+                        || self.layers[0].pin(&hash).is_err()
+                    {
+                        dirty.hashes.remove(&hash);
+                    }
+                }
+                if let Err(e) = self.flush_dirty(&mut dirty) {
+                    // the unwritten objects remain pinned in the first layer, and are retried on
+                    // the next flush
+                    warn!("could not flush to lower layers: {}", e);
+                }
+            }
+            Err(_) => {
+                // without the dirty objects, none can be unpinned or written, so end the first
+                // layer's cycle as usual; the pinned objects survive it
+                warn!("could not flush to lower layers: Lock Poisoned");
+                self.layers[0].end_gc();
+            }
+        }
+        for layer in self.layers[1..].iter() {
+            layer.end_gc();
+        }
+    }
+
+    fn abort_gc(&self) {
+        if let Ok(mut dirty) = self.dirty.lock() {
+            dirty.live = None;
+        }
+        for layer in self.layers.iter() {
+            layer.abort_gc();
+        }
+    }
+}
+
+impl Drop for LayeredStorage {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("could not flush to lower layers: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::traits::StoreAs;
+    use crate::cas::{DiskStorage, GarbageCycle, Storage};
+    use crate::util::test::TempDir;
+    use std::sync::Arc;
+
+    /// A layer that shares its storage with the test, so its content can be examined
+    #[derive(Debug)]
+    struct Shared(Arc<Storage>);
+
+    impl CAS for Shared {
+        fn store(&self, value: Content) -> Fallible<Hash> {
+            self.0.store(value)
+        }
+
+        fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+            self.0.retrieve(hash)
+        }
+
+        fn contains(&self, hash: &Hash) -> Fallible<bool> {
+            self.0.contains(hash)
+        }
+
+        fn resolve_prefix(&self, prefix: &str) -> Fallible<Hash> {
+            self.0.resolve_prefix(prefix)
+        }
+
+        fn touch(&self, hash: &Hash) -> Fallible<()> {
+            self.0.touch(hash)
+        }
+
+        fn pin(&self, hash: &Hash) -> Fallible<()> {
+            self.0.pin(hash)
+        }
+
+        fn unpin(&self, hash: &Hash) -> Fallible<()> {
+            self.0.unpin(hash)
+        }
+
+        fn begin_gc(&self) -> Fallible<()> {
+            self.0.begin_gc()
+        }
+
+        fn end_gc(&self) {
+            self.0.end_gc()
+        }
+
+        fn abort_gc(&self) {
+            self.0.abort_gc()
+        }
+    }
+
+    /// A layer on which every operation fails
+    #[derive(Debug)]
+    struct Broken;
+
+    impl CAS for Broken {
+        fn store(&self, _value: Content) -> Fallible<Hash> {
+            bail!("broken")
+        }
+
+        fn retrieve(&self, _hash: &Hash) -> Fallible<Content> {
+            bail!("broken")
+        }
+
+        fn touch(&self, _hash: &Hash) -> Fallible<()> {
+            bail!("broken")
+        }
+
+        fn begin_gc(&self) -> Fallible<()> {
+            bail!("broken")
+        }
+
+        fn end_gc(&self) {}
+
+        fn abort_gc(&self) {}
+    }
+
+    fn make_layered(policy: WritePolicy) -> (LayeredStorage, Arc<Storage>, Arc<Storage>) {
+        let top = Arc::new(Storage::new());
+        let bottom = Arc::new(Storage::new());
+        let storage = LayeredStorage::new(
+            vec![
+                Box::new(Shared(top.clone())),
+                Box::new(Shared(bottom.clone())),
+            ],
+            policy,
+        )
+        .unwrap();
+        (storage, top, bottom)
+    }
+
+    #[test]
+    fn no_layers() {
+        assert!(LayeredStorage::new(vec![], WritePolicy::WriteThrough).is_err());
+    }
+
+    #[test]
+    fn write_through() {
+        let (storage, top, bottom) = make_layered(WritePolicy::WriteThrough);
+
+        let hash = storage.store(b"abc".to_vec()).unwrap();
+        assert!(top.contains(&hash).unwrap());
+        assert!(bottom.contains(&hash).unwrap());
+        assert_eq!(storage.retrieve(&hash).unwrap(), b"abc".to_vec());
+    }
+
+    #[test]
+    fn write_back() {
+        let (storage, top, bottom) = make_layered(WritePolicy::WriteBack);
+
+        let hash = storage.store(b"abc".to_vec()).unwrap();
+        assert!(top.contains(&hash).unwrap());
+        assert!(!bottom.contains(&hash).unwrap());
+
+        storage.flush().unwrap();
+        assert!(bottom.contains(&hash).unwrap());
+    }
+
+    #[test]
+    fn write_back_on_drop() {
+        let dir = TempDir::new();
+        let hash = {
+            let storage = LayeredStorage::new(
+                vec![
+                    Box::new(Storage::new()),
+                    Box::new(DiskStorage::new(dir.path()).unwrap()),
+                ],
+                WritePolicy::WriteBack,
+            )
+            .unwrap();
+            storage.store(b"abc".to_vec()).unwrap()
+        };
+
+        let storage = DiskStorage::new(dir.path()).unwrap();
+        assert_eq!(storage.retrieve(&hash).unwrap(), b"abc".to_vec());
+    }
+
+    #[test]
+    fn promote_on_read() {
+        let (storage, top, bottom) = make_layered(WritePolicy::WriteThrough);
+
+        let hash = bottom.store(b"deep".to_vec()).unwrap();
+        assert!(storage.contains(&hash).unwrap());
+        assert!(!top.contains(&hash).unwrap());
+
+        assert_eq!(storage.retrieve(&hash).unwrap(), b"deep".to_vec());
+        assert!(top.contains(&hash).unwrap());
+        assert!(storage.retrieve(&Hash::from_hex("1234")).is_err());
+    }
+
+    #[test]
+    fn promote_fails() {
+        let bottom = Arc::new(Storage::new());
+        let storage = LayeredStorage::new(
+            vec![Box::new(Broken), Box::new(Shared(bottom.clone()))],
+            WritePolicy::WriteThrough,
+        )
+        .unwrap();
+
+        // the value is returned even though it could not be promoted
+        let hash = bottom.store(b"deep".to_vec()).unwrap();
+        assert_eq!(storage.retrieve(&hash).unwrap(), b"deep".to_vec());
+    }
+
+    #[test]
+    fn gc() {
+        let (storage, top, bottom) = make_layered(WritePolicy::WriteThrough);
+
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        let hash2 = storage.store(b"def".to_vec()).unwrap();
+        let hash3 = bottom.store(b"ghi".to_vec()).unwrap();
+        {
+            let _gc = GarbageCycle::new(&storage).unwrap();
+            storage.touch(&hash1).unwrap();
+            storage.touch(&hash3).unwrap();
+            assert!(storage.touch(&Hash::from_hex("1234")).is_err());
+        }
+
+        for layer in &[&top, &bottom] {
+            assert!(layer.contains(&hash1).unwrap());
+            assert!(!layer.contains(&hash2).unwrap());
+        }
+        assert!(!top.contains(&hash3).unwrap());
+        assert!(bottom.contains(&hash3).unwrap());
+    }
+
+    #[test]
+    fn gc_write_back() {
+        let (storage, top, bottom) = make_layered(WritePolicy::WriteBack);
+
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        let hash2 = storage.store(b"def".to_vec()).unwrap();
+        {
+            let _gc = GarbageCycle::new(&storage).unwrap();
+            storage.touch(&hash1).unwrap();
+        }
+
+        // only the surviving object is written back
+        assert!(top.contains(&hash1).unwrap());
+        assert!(bottom.contains(&hash1).unwrap());
+        assert!(!top.contains(&hash2).unwrap());
+        assert!(!bottom.contains(&hash2).unwrap());
+    }
+
+    #[test]
+    fn write_back_budget() {
+        let top = Arc::new(Storage::with_budget(6));
+        let bottom = Arc::new(Storage::new());
+        let storage = LayeredStorage::new(
+            vec![
+                Box::new(Shared(top.clone())),
+                Box::new(Shared(bottom.clone())),
+            ],
+            WritePolicy::WriteBack,
+        )
+        .unwrap();
+
+        // unwritten objects are not evicted, even though they exceed the budget
+        let hashes: Vec<_> = ["abc", "def", "ghi"]
+            .iter()
+            .map(|v| storage.store(v.as_bytes().to_vec()).unwrap())
+            .collect();
+        for hash in hashes.iter() {
+            assert!(top.contains(hash).unwrap());
+            assert!(!bottom.contains(hash).unwrap());
+        }
+
+        // once written, they can be evicted
+        storage.flush().unwrap();
+        let hash4 = storage.store(b"jkl".to_vec()).unwrap();
+        assert!(!top.contains(&hashes[0]).unwrap());
+        assert!(top.contains(&hash4).unwrap());
+        for hash in hashes.iter() {
+            assert_eq!(
+                storage.retrieve(hash).unwrap(),
+                bottom.retrieve(hash).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn gc_write_back_budget() {
+        let top = Arc::new(Storage::with_budget(3));
+        let bottom = Arc::new(Storage::new());
+        let storage = LayeredStorage::new(
+            vec![
+                Box::new(Shared(top.clone())),
+                Box::new(Shared(bottom.clone())),
+            ],
+            WritePolicy::WriteBack,
+        )
+        .unwrap();
+
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        let hash2 = storage.store(b"def".to_vec()).unwrap();
+        top.pin(&hash2).unwrap();
+        let hash3 = storage.store(b"ghi".to_vec()).unwrap();
+        {
+            let _gc = GarbageCycle::new(&storage).unwrap();
+            storage.touch(&hash1).unwrap();
+        }
+
+        // the touched object and the object pinned by the caller are written back, and the
+        // garbage is not
+        assert!(bottom.contains(&hash1).unwrap());
+        assert!(bottom.contains(&hash2).unwrap());
+        assert!(!top.contains(&hash3).unwrap());
+        assert!(!bottom.contains(&hash3).unwrap());
+
+        // only the caller's pin remains
+        top.unpin(&hash2).unwrap();
+        assert!(top.unpin(&hash2).is_err());
+    }
+
+    #[test]
+    fn write_back_caller_pins() {
+        let (storage, top, bottom) = make_layered(WritePolicy::WriteBack);
+        let hash = storage.store(b"abc".to_vec()).unwrap();
+
+        // the caller cannot remove the pin held until the object is written
+        assert!(storage.unpin(&hash).is_err());
+        storage.pin(&hash).unwrap();
+        storage.unpin(&hash).unwrap();
+        assert!(storage.unpin(&hash).is_err());
+
+        storage.flush().unwrap();
+        assert!(bottom.contains(&hash).unwrap());
+        assert!(top.unpin(&hash).is_err());
+    }
+
+    #[test]
+    fn flush_unpin_fails() {
+        let (storage, top, bottom) = make_layered(WritePolicy::WriteBack);
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        let hash2 = storage.store(b"def".to_vec()).unwrap();
+
+        // remove the write-back pin behind the LayeredStorage's back
+        top.unpin(&hash1).unwrap();
+        top.unpin(&hash2).unwrap();
+
+        // the objects are still written, and later flushes are not stuck on them
+        storage.flush().unwrap();
+        assert!(bottom.contains(&hash1).unwrap());
+        assert!(bottom.contains(&hash2).unwrap());
+        let hash3 = storage.store(b"ghi".to_vec()).unwrap();
+        storage.flush().unwrap();
+        assert!(bottom.contains(&hash3).unwrap());
+    }
+
+    #[test]
+    fn resolve_prefix() {
+        let (storage, top, bottom) = make_layered(WritePolicy::WriteBack);
+        StoreAs::store_as(&*top, &Hash::from_hex("012345"), b"a".to_vec()).unwrap();
+        StoreAs::store_as(&*top, &Hash::from_hex("abcd01"), b"b".to_vec()).unwrap();
+        StoreAs::store_as(&*top, &Hash::from_hex("abcd02"), b"c".to_vec()).unwrap();
+        StoreAs::store_as(&*bottom, &Hash::from_hex("012345"), b"a".to_vec()).unwrap();
+        StoreAs::store_as(&*bottom, &Hash::from_hex("012399"), b"d".to_vec()).unwrap();
+
+        // the same object in two layers is a single candidate
+        assert_eq!(
+            storage.resolve_prefix("01234").unwrap(),
+            Hash::from_hex("012345")
+        );
+
+        // different objects in different layers are ambiguous
+        match storage
+            .resolve_prefix("0123")
+            .unwrap_err()
+            .downcast::<Error>()
+        {
+            Ok(Error::AmbiguousPrefix(_, candidates)) => assert_eq!(
+                candidates,
+                vec![Hash::from_hex("012345"), Hash::from_hex("012399")]
+            ),
+            e => panic!("unexpected {:?}", e),
+        }
+
+        // ambiguity in one layer is not hidden by another layer not finding the prefix
+        match storage
+            .resolve_prefix("abcd")
+            .unwrap_err()
+            .downcast::<Error>()
+        {
+            Ok(Error::AmbiguousPrefix(..)) => {}
+            e => panic!("unexpected {:?}", e),
+        }
+
+        match storage
+            .resolve_prefix("9999")
+            .unwrap_err()
+            .downcast::<Error>()
+        {
+            Ok(Error::PrefixNotFound(_)) => {}
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn begin_gc_fails() {
+        let top = Arc::new(Storage::new());
+        let storage = LayeredStorage::new(
+            vec![Box::new(Shared(top.clone())), Box::new(Broken)],
+            WritePolicy::WriteBack,
+        )
+        .unwrap();
+        let hash = top.store(b"abc".to_vec()).unwrap();
+
+        assert!(GarbageCycle::new(&storage).is_err());
+
+        // the first layer's cycle was aborted, so its generations are still in step and a later
+        // cycle collects normally
+        let stats = top.stats().unwrap();
+        assert_eq!(stats.garbage_generation + 1, stats.cur_generation);
+        top.begin_gc().unwrap();
+        top.end_gc();
+        assert!(!top.contains(&hash).unwrap());
+    }
+
+    #[test]
+    fn pin() {
+        let (storage, top, bottom) = make_layered(WritePolicy::WriteThrough);
+
+        let hash = storage.store(b"abc".to_vec()).unwrap();
+        storage.pin(&hash).unwrap();
+        assert!(storage.pin(&Hash::from_hex("1234")).is_err());
+        {
+            let _gc = GarbageCycle::new(&storage).unwrap();
+        }
+        assert!(top.contains(&hash).unwrap());
+        assert!(bottom.contains(&hash).unwrap());
+
+        storage.unpin(&hash).unwrap();
+        assert!(storage.unpin(&hash).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+//! Support for bounding the memory used by a storage pool.
+
+use super::hash::Hash;
+use std::collections::{BTreeMap, HashMap};
+
+/// Lru tracks the total size of the objects in a storage pool with a byte budget, and the order
+/// in which they were used, so that the least-recently-used objects can be evicted.
+#[derive(Debug)]
+pub(crate) struct Lru {
+    budget: u64,
+    bytes: u64,
+
+    /// A counter, incremented on every use
+    clock: u64,
+
+    /// Hashes by the time they were last used
+    order: BTreeMap<u64, Hash>,
+
+    /// The time each hash was last used
+    last_used: HashMap<Hash, u64>,
+
+    objects_evicted: u64,
+    bytes_evicted: u64,
+}
+
+impl Lru {
+    pub(crate) fn new(budget: u64) -> Lru {
+        Lru {
+            budget,
+            bytes: 0,
+            clock: 0,
+            order: BTreeMap::new(),
+            last_used: HashMap::new(),
+            objects_evicted: 0,
+            bytes_evicted: 0,
+        }
+    }
+
+    /// Record a use of the given object
+    pub(crate) fn used(&mut self, hash: &Hash) {
+        self.clock += 1;
+        if let Some(previous) = self.last_used.insert(hash.clone(), self.clock) {
+            self.order.remove(&previous);
+        }
+        self.order.insert(self.clock, hash.clone());
+    }
+
+    /// Record the addition of an object, which counts as a use
+    pub(crate) fn added(&mut self, hash: &Hash, size: u64) {
+        self.bytes += size;
+        self.used(hash);
+    }
+
+    /// Record the removal of an object
+    pub(crate) fn removed(&mut self, hash: &Hash, size: u64) {
+        self.bytes -= size;
+        if let Some(previous) = self.last_used.remove(hash) {
+            self.order.remove(&previous);
+        }
+    }
+
+    /// Record the removal of an object by eviction
+    pub(crate) fn evicted(&mut self, hash: &Hash, size: u64) {
+        self.removed(hash, size);
+        self.objects_evicted += 1;
+        self.bytes_evicted += size;
+    }
+
+    /// The number of bytes by which the objects exceed the budget
+    pub(crate) fn excess(&self) -> u64 {
+        self.bytes.saturating_sub(self.budget)
+    }
+
+    /// Iterate over objects, least-recently-used first
+    pub(crate) fn least_recent(&self) -> impl Iterator<Item = &Hash> {
+        self.order.values()
+    }
+
+    /// Get the total number and size of objects evicted so far
+    pub(crate) fn evictions(&self) -> (u64, u64) {
+        (self.objects_evicted, self.bytes_evicted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn order() {
+        let mut lru = Lru::new(10);
+        let (a, b, c) = (
+            Hash::from_hex("aa"),
+            Hash::from_hex("bb"),
+            Hash::from_hex("cc"),
+        );
+        lru.added(&a, 4);
+        lru.added(&b, 4);
+        lru.added(&c, 4);
+        assert_eq!(lru.excess(), 2);
+
+        lru.used(&a);
+        let order: Vec<&Hash> = lru.least_recent().collect();
+        assert_eq!(order, vec![&b, &c, &a]);
+
+        lru.evicted(&b, 4);
+        assert_eq!(lru.excess(), 0);
+        assert_eq!(lru.least_recent().count(), 2);
+        assert_eq!(lru.evictions(), (1, 4));
+    }
+}
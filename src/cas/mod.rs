@@ -3,7 +3,7 @@
 //! all content, and supports generational garbage collection and persistence
 //! to disk.
 //!
-//! The API is in the `CAS` trait.
+//! The API is in the `CAS` trait, with an asynchronous equivalent in the `AsyncCAS` trait.
 //!
 //! # Warning
 //!
@@ -36,15 +36,42 @@
 //! }
 //! ```
 
+mod async_cas;
+mod checkpoint;
+mod compressed;
+mod disk;
+mod encrypted;
 mod gc;
 mod hash;
+mod layered;
+mod lru;
+mod pack;
+mod pin;
+mod prefix;
+mod replicated;
+mod scrub;
+mod sharded;
+mod stats;
 mod storage;
 mod traits;
 
+pub use self::async_cas::{AsyncAdapter, AsyncCAS, SyncAdapter};
+pub use self::checkpoint::CheckpointStorage;
+pub use self::compressed::{CompressedStorage, DEFAULT_COMPRESSION_THRESHOLD};
+pub use self::disk::DiskStorage;
+pub use self::encrypted::{EncryptedStorage, EncryptionKey};
 pub use self::gc::GarbageCycle;
-pub use self::hash::Hash;
+pub use self::hash::{Algorithm, Hash};
+pub use self::layered::{Layer, LayeredStorage, WritePolicy};
+pub use self::pack::{export_pack, import_pack};
+pub use self::pin::PinGuard;
+pub use self::prefix::MIN_PREFIX_LEN;
+pub use self::replicated::ReplicatedStorage;
+pub use self::scrub::{scrub, ScrubReport};
+pub use self::sharded::ShardedStorage;
+pub use self::stats::{GcStats, Stats};
 pub use self::storage::Storage;
-pub use self::traits::CAS;
+pub use self::traits::{ObjectInfo, Objects, RawCAS, CAS};
 
 pub use self::storage::Storage as LocalStorage;
 
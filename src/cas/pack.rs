@@ -0,0 +1,257 @@
+use super::hash::Hash;
+use super::traits::CAS;
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use failure::{bail, Fallible};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use log::debug;
+use std::io::{Read, Write};
+
+/// Identifies a pack file
+const MAGIC: &[u8; 8] = b"RBSHPACK";
+
+/// The current pack format version
+const VERSION: u32 = 1;
+
+/// The longest hash accepted when reading a pack
+const MAX_HASH_LEN: usize = 64;
+
+/// Body encodings
+const ENCODING_RAW: u8 = 0;
+const ENCODING_DEFLATE: u8 = 1;
+
+/// An entry in the pack index
+struct IndexEntry {
+    hash: Hash,
+    offset: u64,
+    size: u64,
+}
+
+/// Write a pack containing the objects with the given hashes to the given writer.  A pack is a
+/// portable archive of objects, suitable for moving data between clusters or for offline backups.
+/// Its format is
+///
+/// ```text
+/// header:  magic ("RBSHPACK") | version (u32) | object count (u32)
+/// index:   for each object, sorted by hash:
+///            hash length (u32) | hash | body offset (u64) | content length (u64)
+/// bodies:  for each object, in index order:
+///            encoding (u8) | body length (u64) | body
+/// ```
+///
+/// with all integers in network byte order.  Body offsets are relative to the beginning of the
+/// bodies.  Bodies are compressed with DEFLATE (encoding 1) if that makes them smaller, and
+/// otherwise stored raw (encoding 0).
+///
+/// The compressed bodies are held in memory until the index is written.
+pub fn export_pack<ST, W>(storage: &ST, hashes: &[Hash], mut writer: W) -> Fallible<()>
+where
+    ST: CAS + ?Sized,
+    W: Write,
+{
+    let mut hashes = hashes.to_vec();
+    hashes.sort();
+    hashes.dedup();
+
+    let mut index = vec![];
+    let mut bodies = vec![];
+    let mut offset = 0;
+    for hash in hashes {
+        let content = storage.retrieve(&hash)?;
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        encoder.write_all(&content)?;
+        let compressed = encoder.finish()?;
+        let (encoding, body) = if compressed.len() < content.len() {
+            (ENCODING_DEFLATE, compressed)
+        } else {
+            (ENCODING_RAW, content.clone())
+        };
+        index.push(IndexEntry {
+            hash,
+            offset,
+            size: content.len() as u64,
+        });
+        offset += 1 + 8 + body.len() as u64;
+        bodies.push((encoding, body));
+    }
+
+    writer.write_all(MAGIC)?;
+    writer.write_u32::<NetworkEndian>(VERSION)?;
+    writer.write_u32::<NetworkEndian>(index.len() as u32)?;
+    for entry in index.iter() {
+        let hash = entry.hash.as_bytes();
+        writer.write_u32::<NetworkEndian>(hash.len() as u32)?;
+        writer.write_all(hash)?;
+        writer.write_u64::<NetworkEndian>(entry.offset)?;
+        writer.write_u64::<NetworkEndian>(entry.size)?;
+    }
+    for (encoding, body) in bodies.iter() {
+        writer.write_u8(*encoding)?;
+        writer.write_u64::<NetworkEndian>(body.len() as u64)?;
+        writer.write_all(body)?;
+    }
+    writer.flush()?;
+    debug!("exported pack of {} objects", index.len());
+    Ok(())
+}
+
+/// Read a pack written by `export_pack` from the given reader, storing each object it contains
+/// and returning their hashes.  Every object is checked against its hash before it is stored, and
+/// any problem with the pack is an error.  Objects stored before such an error remain in storage.
+///
+/// The storage must use the same hash algorithm as the storage from which the pack was exported.
+pub fn import_pack<ST, R>(storage: &ST, mut reader: R) -> Fallible<Vec<Hash>>
+where
+    ST: CAS + ?Sized,
+    R: Read,
+{
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("not a pack file");
+    }
+    let version = reader.read_u32::<NetworkEndian>()?;
+    if version != VERSION {
+        bail!("unsupported pack version {}", version);
+    }
+
+    let count = reader.read_u32::<NetworkEndian>()?;
+    let mut index: Vec<IndexEntry> = vec![];
+    for _ in 0..count {
+        let hash_len = reader.read_u32::<NetworkEndian>()? as usize;
+        if hash_len > MAX_HASH_LEN {
+            bail!("invalid hash length {} in pack index", hash_len);
+        }
+        let mut hash = vec![0u8; hash_len];
+        reader.read_exact(&mut hash)?;
+        let hash = Hash::from_bytes(hash);
+        if let Some(prev) = index.last() {
+            if prev.hash >= hash {
+                bail!("pack index is not sorted");
+            }
+        }
+        let offset = reader.read_u64::<NetworkEndian>()?;
+        let size = reader.read_u64::<NetworkEndian>()?;
+        index.push(IndexEntry { hash, offset, size });
+    }
+
+    let mut hashes = vec![];
+    let mut offset = 0;
+    for entry in index {
+        if entry.offset != offset {
+            bail!(
+                "pack body for {:?} is not at the indexed offset",
+                entry.hash
+            );
+        }
+        let encoding = reader.read_u8()?;
+        let body_len = reader.read_u64::<NetworkEndian>()?;
+        let mut body = vec![];
+        reader.by_ref().take(body_len).read_to_end(&mut body)?;
+        if body.len() as u64 != body_len {
+            bail!("pack is truncated");
+        }
+        offset += 1 + 8 + body_len;
+
+        let content = match encoding {
+            ENCODING_RAW => body,
+            ENCODING_DEFLATE => {
+                // read one byte more than expected, to detect content that is too long
+                let mut content = vec![];
+                DeflateDecoder::new(&body[..])
+                    .take(entry.size + 1)
+                    .read_to_end(&mut content)?;
+                content
+            }
+            _ => bail!("unknown pack body encoding {}", encoding),
+        };
+        if content.len() as u64 != entry.size || !entry.hash.verify(&content) {
+            bail!("pack object does not match its hash {:?}", entry.hash);
+        }
+
+        if storage.store(content)? != entry.hash {
+            bail!("{:?} was stored under a different hash", entry.hash);
+        }
+        hashes.push(entry.hash);
+    }
+    debug!("imported pack of {} objects", hashes.len());
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::{Algorithm, Storage};
+
+    /// Make a storage with some objects in it, returning the storage and hashes
+    fn make_storage(algorithm: Algorithm) -> (Storage, Vec<Hash>) {
+        let storage = Storage::with_algorithm(algorithm);
+        let hashes = storage
+            .store_many(vec![
+                b"short".to_vec(),
+                vec![7u8; 10000], // compresses well
+                vec![],
+            ])
+            .unwrap();
+        (storage, hashes)
+    }
+
+    #[test]
+    fn round_trip() {
+        for algorithm in &[Algorithm::Sha256, Algorithm::Blake3] {
+            let (storage, hashes) = make_storage(*algorithm);
+            let mut pack = vec![];
+            export_pack(&storage, &hashes, &mut pack).unwrap();
+            // the repetitive object was compressed
+            assert!(pack.len() < 1000);
+
+            let storage2 = Storage::with_algorithm(*algorithm);
+            let mut imported = import_pack(&storage2, &pack[..]).unwrap();
+            imported.sort();
+            let mut expected = hashes.clone();
+            expected.sort();
+            assert_eq!(imported, expected);
+            for hash in hashes.iter() {
+                assert_eq!(
+                    storage2.retrieve(hash).unwrap(),
+                    storage.retrieve(hash).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn export_missing() {
+        let (storage, _) = make_storage(Algorithm::Sha256);
+        let mut pack = vec![];
+        assert!(export_pack(&storage, &[Hash::from_hex("1234")], &mut pack).is_err());
+    }
+
+    #[test]
+    fn import_corrupt() {
+        let storage = Storage::new();
+        let hash = storage.store(b"precious".to_vec()).unwrap();
+        let mut pack = vec![];
+        export_pack(&storage, &[hash], &mut pack).unwrap();
+
+        // flip a bit in the body, which is stored raw at the end of the pack
+        let last = pack.len() - 1;
+        pack[last] ^= 1;
+
+        let storage2 = Storage::new();
+        assert!(import_pack(&storage2, &pack[..]).is_err());
+        assert_eq!(storage2.stats().unwrap().objects, 0);
+    }
+
+    #[test]
+    fn import_truncated() {
+        let (storage, hashes) = make_storage(Algorithm::Sha256);
+        let mut pack = vec![];
+        export_pack(&storage, &hashes, &mut pack).unwrap();
+
+        for len in &[0, 4, 20, pack.len() - 1] {
+            assert!(import_pack(&Storage::new(), &pack[..*len]).is_err());
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Support for pinning objects so that they survive garbage collection.
+
+use super::hash::Hash;
+use super::traits::CAS;
+use failure::{bail, Fallible};
+use log::warn;
+use std::collections::HashMap;
+
+/// Pins tracks the reference counts of pinned objects, for use by `CAS` implementations.
+#[derive(Debug, Default)]
+pub(crate) struct Pins(HashMap<Hash, usize>);
+
+impl Pins {
+    /// Increment the pin count for the given hash
+    pub(crate) fn pin(&mut self, hash: &Hash) {
+        *self.0.entry(hash.clone()).or_insert(0) += 1;
+    }
+
+    /// Decrement the pin count for the given hash, failing if it is not pinned
+    pub(crate) fn unpin(&mut self, hash: &Hash) -> Fallible<()> {
+        match self.0.get_mut(hash) {
+            None => bail!("{:?} is not pinned", hash),
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => {
+                self.0.remove(hash);
+            }
+        }
+        Ok(())
+    }
+
+    /// Determine whether the given hash is pinned
+    pub(crate) fn is_pinned(&self, hash: &Hash) -> bool {
+        self.0.contains_key(hash)
+    }
+
+    /// Get the hashes of all pinned objects
+    pub(crate) fn hashes(&self) -> Vec<Hash> {
+        self.0.keys().cloned().collect()
+    }
+}
+
+/// Type PinGuard pins an object for as long as it exists, unpinning it when dropped.
+///
+/// # Examples
+///
+/// ```
+/// use rubbish::cas::{Storage, GarbageCycle, PinGuard, CAS};
+/// let storage = Storage::new();
+///
+/// let hash = storage.store(vec![1, 2]).unwrap();
+/// let pin = PinGuard::new(&storage, &hash).unwrap();
+/// {
+///     let gc = GarbageCycle::new(&storage);
+///     // hash is not touched..
+/// }
+///
+/// // ..but survives, since it is pinned
+/// assert!(storage.retrieve(&hash).is_ok());
+/// ```
+pub struct PinGuard<'a, ST: 'a + CAS + ?Sized> {
+    storage: &'a ST,
+    hash: Hash,
+}
+
+impl<'a, ST: 'a + CAS + ?Sized> PinGuard<'a, ST> {
+    pub fn new(storage: &'a ST, hash: &Hash) -> Fallible<PinGuard<'a, ST>> {
+        storage.pin(hash)?;
+        Ok(PinGuard {
+            storage,
+            hash: hash.clone(),
+        })
+    }
+
+    /// Get the hash of the pinned object
+    pub fn hash(&self) -> &Hash {
+        &self.hash
+    }
+}
+
+impl<'a, ST: 'a + CAS + ?Sized> Drop for PinGuard<'a, ST> {
+    fn drop(&mut self) {
+        if let Err(e) = self.storage.unpin(&self.hash) {
+            warn!("could not unpin {:?}: {}", self.hash, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::{GarbageCycle, Storage};
+
+    #[test]
+    fn refcount() {
+        let mut pins = Pins::default();
+        let hash = Hash::from_hex("1234");
+        pins.pin(&hash);
+        pins.pin(&hash);
+        pins.unpin(&hash).unwrap();
+        assert!(pins.is_pinned(&hash));
+        assert_eq!(pins.hashes(), vec![hash.clone()]);
+        pins.unpin(&hash).unwrap();
+        assert!(!pins.is_pinned(&hash));
+        assert_eq!(pins.hashes(), vec![]);
+        assert!(pins.unpin(&hash).is_err());
+    }
+
+    #[test]
+    fn guard() {
+        let storage = Storage::new();
+        let hash = storage.store(b"pinned".to_vec()).unwrap();
+
+        {
+            let pin = PinGuard::new(&storage, &hash).unwrap();
+            assert_eq!(pin.hash(), &hash);
+            let _gc = GarbageCycle::new(&storage).unwrap();
+        }
+        assert!(storage.retrieve(&hash).is_ok());
+
+        // the guard has been dropped, so the next cycle collects the object
+        {
+            let _gc = GarbageCycle::new(&storage).unwrap();
+        }
+        assert!(storage.retrieve(&hash).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use super::error::Error;
+use super::hash::Hash;
+use failure::Fallible;
+use rustc_serialize::hex::FromHex;
+use std::collections::BTreeMap;
+
+/// The shortest hex prefix that will be resolved, to avoid listing huge numbers of candidates
+pub const MIN_PREFIX_LEN: usize = 4;
+
+/// Resolve an abbreviated hex prefix to the unique matching key in the given map.  Since the map
+/// is ordered by hash, this only examines the keys matching the prefix.
+pub(crate) fn resolve_prefix<V>(map: &BTreeMap<Hash, V>, prefix: &str) -> Fallible<Hash> {
+    let prefix = prefix.to_lowercase();
+    let candidates = matching_prefix(map, &prefix)?;
+    unique_match(prefix, candidates)
+}
+
+/// Find all keys in the given map matching a lowercase hex prefix, in order.
+pub(crate) fn matching_prefix<V>(map: &BTreeMap<Hash, V>, prefix: &str) -> Fallible<Vec<Hash>> {
+    if prefix.len() < MIN_PREFIX_LEN {
+        return Err(Error::InvalidPrefix(prefix.to_string()).into());
+    }
+
+    // an odd-length prefix covers the range beginning with its "0" completion
+    let mut start = prefix.to_string();
+    if start.len() % 2 == 1 {
+        start.push('0');
+    }
+    let start = match start.from_hex() {
+        Ok(bytes) => Hash::from_bytes(bytes),
+        Err(_) => return Err(Error::InvalidPrefix(prefix.to_string()).into()),
+    };
+
+    Ok(map
+        .range(start..)
+        .map(|(hash, _)| hash)
+        .take_while(|hash| hash.to_hex().starts_with(prefix))
+        .cloned()
+        .collect())
+}
+
+/// Return the single candidate matching a prefix, or an appropriate error.
+pub(crate) fn unique_match(prefix: String, mut candidates: Vec<Hash>) -> Fallible<Hash> {
+    match candidates.len() {
+        0 => Err(Error::PrefixNotFound(prefix).into()),
+        1 => Ok(candidates.pop().unwrap()),
+        _ => Err(Error::AmbiguousPrefix(prefix, candidates).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_map(hashes: &[&str]) -> BTreeMap<Hash, ()> {
+        hashes.iter().map(|h| (Hash::from_hex(h), ())).collect()
+    }
+
+    #[test]
+    fn unique() {
+        let map = make_map(&["012345", "012399", "abcdef"]);
+        assert_eq!(
+            resolve_prefix(&map, "01234").unwrap(),
+            Hash::from_hex("012345")
+        );
+        assert_eq!(
+            resolve_prefix(&map, "ABCD").unwrap(),
+            Hash::from_hex("abcdef")
+        );
+    }
+
+    #[test]
+    fn ambiguous() {
+        let map = make_map(&["012345", "012399", "abcdef"]);
+        match resolve_prefix(&map, "0123")
+            .unwrap_err()
+            .downcast::<Error>()
+        {
+            Ok(Error::AmbiguousPrefix(p, candidates)) => {
+                assert_eq!(p, "0123");
+                assert_eq!(
+                    candidates,
+                    vec![Hash::from_hex("012345"), Hash::from_hex("012399")]
+                );
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn odd_length() {
+        let map = make_map(&["012345", "012355", "012365"]);
+        assert_eq!(
+            resolve_prefix(&map, "01235").unwrap(),
+            Hash::from_hex("012355")
+        );
+        assert!(resolve_prefix(&map, "01236").is_ok());
+        assert!(resolve_prefix(&map, "01237").is_err());
+    }
+
+    #[test]
+    fn not_found() {
+        let map = make_map(&["012345"]);
+        match resolve_prefix(&map, "9999")
+            .unwrap_err()
+            .downcast::<Error>()
+        {
+            Ok(Error::PrefixNotFound(p)) => assert_eq!(p, "9999"),
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let map = make_map(&["012345"]);
+        for prefix in &["01", "xyzw", ""] {
+            match resolve_prefix(&map, prefix)
+                .unwrap_err()
+                .downcast::<Error>()
+            {
+                Ok(Error::InvalidPrefix(_)) => {}
+                e => panic!("unexpected {:?}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::cas::traits::Content;
+use crate::cas::Hash;
+use byteorder::{ByteOrder, NetworkEndian};
+use failure::{bail, Fallible};
+
+/// Messages exchanged between the nodes of a ReplicatedStorage.
+#[derive(Debug, PartialEq)]
+pub(super) enum Message {
+    /// The sender has these objects
+    Have(Vec<Hash>),
+
+    /// The sender would like to receive these objects.  The request id is echoed in any `Missing`
+    /// reply, so that replies to different requests for the same objects can be told apart.
+    Want(u32, Vec<Hash>),
+
+    /// An object, sent in response to Want
+    Object(Hash, Content),
+
+    /// The sender does not have these objects, sent in response to the Want with this request id
+    Missing(u32, Vec<Hash>),
+}
+
+const HAVE: u8 = 1;
+const WANT: u8 = 2;
+const OBJECT: u8 = 3;
+const MISSING: u8 = 4;
+
+impl Message {
+    pub(super) fn serialize(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        match self {
+            Message::Have(hashes) => {
+                buf.push(HAVE);
+                write_hashes(&mut buf, hashes);
+            }
+            Message::Want(id, hashes) => {
+                buf.push(WANT);
+                write_u32(&mut buf, *id);
+                write_hashes(&mut buf, hashes);
+            }
+            Message::Object(hash, content) => {
+                buf.push(OBJECT);
+                write_bytes(&mut buf, hash.as_bytes());
+                write_bytes(&mut buf, content);
+            }
+            Message::Missing(id, hashes) => {
+                buf.push(MISSING);
+                write_u32(&mut buf, *id);
+                write_hashes(&mut buf, hashes);
+            }
+        }
+        buf
+    }
+
+    pub(super) fn deserialize(ser: &[u8]) -> Fallible<Self> {
+        let mut reader = Reader(ser);
+        let message = match reader.u8()? {
+            HAVE => Message::Have(reader.hashes()?),
+            WANT => Message::Want(reader.u32()?, reader.hashes()?),
+            OBJECT => {
+                let hash = Hash::from_bytes(reader.bytes()?);
+                Message::Object(hash, reader.bytes()?)
+            }
+            MISSING => Message::Missing(reader.u32()?, reader.hashes()?),
+            t => bail!("unknown message type {}", t),
+        };
+        if !reader.0.is_empty() {
+            bail!("trailing bytes in message");
+        }
+        Ok(message)
+    }
+}
+
+fn write_u32(buf: &mut Vec<u8>, v: u32) {
+    let mut b = [0u8; 4];
+    NetworkEndian::write_u32(&mut b, v);
+    buf.extend_from_slice(&b);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(buf, bytes.len() as u32);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_hashes(buf: &mut Vec<u8>, hashes: &[Hash]) {
+    write_u32(buf, hashes.len() as u32);
+    for hash in hashes {
+        write_bytes(buf, hash.as_bytes());
+    }
+}
+
+/// A cursor over a serialized message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Fallible<&'a [u8]> {
+        if self.0.len() < n {
+            bail!("message truncated");
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Fallible<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Fallible<u32> {
+        Ok(NetworkEndian::read_u32(self.take(4)?))
+    }
+
+    fn bytes(&mut self) -> Fallible<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn hashes(&mut self) -> Fallible<Vec<Hash>> {
+        let count = self.u32()?;
+        let mut hashes = vec![];
+        for _ in 0..count {
+            hashes.push(Hash::from_bytes(self.bytes()?));
+        }
+        Ok(hashes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(message: Message) {
+        let ser = message.serialize();
+        assert_eq!(Message::deserialize(&ser).unwrap(), message);
+    }
+
+    #[test]
+    fn round_trip_have() {
+        round_trip(Message::Have(vec![
+            Hash::from_hex("0123"),
+            Hash::from_hex("456789"),
+        ]));
+    }
+
+    #[test]
+    fn round_trip_want() {
+        round_trip(Message::Want(0, vec![]));
+        round_trip(Message::Want(7, vec![Hash::from_hex("0123")]));
+    }
+
+    #[test]
+    fn round_trip_object() {
+        round_trip(Message::Object(Hash::from_hex("abcd"), vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn round_trip_missing() {
+        round_trip(Message::Missing(7, vec![Hash::from_hex("abcd")]));
+    }
+
+    #[test]
+    fn truncated() {
+        let ser = Message::Object(Hash::from_hex("abcd"), vec![1, 2, 3]).serialize();
+        assert!(Message::deserialize(&ser[..ser.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,884 @@
+//! Replication of content between the nodes of a network.
+//!
+//! Each node runs a `ReplicatedStorage` wrapping a local storage pool.  Whenever an object is
+//! stored, its hash is advertised to all peers with a `Have` message.  A peer that does not
+//! already have that object replies with `Want`, and the object is sent in an `Object` message.
+//! A node receiving a new object stores it locally and advertises it in turn, so content spreads
+//! through the network even if some messages between pairs of nodes are lost.
+//!
+//! Content that a node has missed entirely (for example, content stored before the node joined
+//! the network) is fetched on demand: when `retrieve` or `touch` finds that an object is missing
+//! locally, the node sends `Want` to all peers and waits for one of them to send the object.
+//! Peers lacking the object reply with `Missing`, so a fetch for an object that exists nowhere
+//! fails quickly.
+
+mod message;
+
+use self::message::Message;
+use crate::cas::traits::{Content, Objects, CAS};
+use crate::cas::{self, Hash, Stats};
+use crate::net::{NetworkNode, NodeId};
+use async_trait::async_trait;
+use failure::{bail, err_msg, Fallible};
+use log::{debug, warn};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use tokio::time::timeout;
+
+/// Default time to wait for peers to supply a missing object.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Type ReplicatedStorage provides a content-addressible storage pool whose content is
+/// replicated to all other nodes in a network.
+///
+/// Replication occurs in a background task, so an object stored on one node becomes available
+/// on the other nodes shortly afterward.  The storage must be created within a Tokio runtime.
+///
+/// Garbage collection is local to each node: `begin_gc`, `touch`, and `end_gc` apply only to
+/// the local storage pool.
+///
+/// Objects are addressed by the hash the storing node calculated, so all nodes' local storage
+/// pools should use the same hash algorithm.
+///
+/// When an object is not available locally, `retrieve` and `touch` fetch it from peers, verify
+/// its hash, and store it in the current generation of the local storage pool.  These methods
+/// block the calling thread while waiting for the network, so they must not be called from a
+/// thread that the background task depends on; use `tokio::task::spawn_blocking` or
+/// `tokio::task::block_in_place` when calling them from async code.  The `AsyncCAS`
+/// implementation does not have this restriction.
+pub struct ReplicatedStorage<ST>
+where
+    ST: CAS + Send + Sync + 'static,
+{
+    /// The local storage pool, shared with the background task
+    local: Arc<ST>,
+
+    /// The background task exchanging messages with peers
+    task: task::JoinHandle<()>,
+
+    /// A channel to send control messages to the background task
+    control_tx: mpsc::UnboundedSender<Control>,
+
+    /// Time to wait for peers to supply a missing object
+    fetch_timeout: Duration,
+}
+
+/// Control messages sent from a ReplicatedStorage to its background task
+#[derive(Debug)]
+enum Control {
+    /// Stop the task
+    Stop,
+
+    /// Use this time to wait for peers to supply a missing object
+    SetFetchTimeout(Duration),
+
+    /// Advertise this newly-stored object to peers
+    Advertise(Hash),
+
+    /// Fetch this object from peers, storing it locally and sending it to the given waiter, or
+    /// sending None if no peer has it
+    Fetch(Hash, Waiter),
+}
+
+/// A caller waiting for the result of a fetch
+#[derive(Debug)]
+enum Waiter {
+    /// A synchronous caller, blocked in `CAS` methods
+    Sync(std_mpsc::Sender<Option<Content>>),
+
+    /// An asynchronous caller, awaiting in `AsyncCAS` methods
+    Async(oneshot::Sender<Option<Content>>),
+}
+
+impl Waiter {
+    /// Determine whether the caller has given up waiting, having begun waiting at `since`
+    fn is_gone(&self, since: Instant, fetch_timeout: Duration) -> bool {
+        match self {
+            // a synchronous caller cannot be observed, but gives up after the fetch timeout
+            Waiter::Sync(_) => since.elapsed() >= fetch_timeout,
+            Waiter::Async(tx) => tx.is_closed(),
+        }
+    }
+
+    fn send(self, content: Option<Content>) {
+        // the waiter may have timed out and gone away, so ignore errors
+        match self {
+            Waiter::Sync(tx) => {
+                let _ = tx.send(content);
+            }
+            Waiter::Async(tx) => {
+                let _ = tx.send(content);
+            }
+        }
+    }
+}
+
+impl<ST> ReplicatedStorage<ST>
+where
+    ST: CAS + Send + Sync + 'static,
+{
+    /// Create a new replicated storage pool, storing content locally in `local` and
+    /// communicating with peers via `node`.
+    pub fn new<NODE>(node: NODE, local: ST) -> ReplicatedStorage<ST>
+    where
+        NODE: NetworkNode + Sync + Send + 'static,
+    {
+        let local = Arc::new(local);
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        let inner = ReplicationInner {
+            node,
+            local: local.clone(),
+            control_rx,
+            fetches: HashMap::new(),
+            fetch_timeout: FETCH_TIMEOUT,
+            next_request: 1,
+        };
+
+        ReplicatedStorage {
+            local,
+            task: tokio::spawn(async move { inner.run().await }),
+            control_tx,
+            fetch_timeout: FETCH_TIMEOUT,
+        }
+    }
+
+    /// Set the time to wait for peers to supply a missing object.
+    pub fn with_fetch_timeout(mut self, fetch_timeout: Duration) -> Self {
+        self.fetch_timeout = fetch_timeout;
+        // if the send fails, the task has already exited
+        let _ = self
+            .control_tx
+            .send(Control::SetFetchTimeout(fetch_timeout));
+        self
+    }
+
+    /// Fetch a missing object from peers, blocking until it arrives.  The background task stores
+    /// the object locally before sending it here.
+    fn fetch(&self, hash: &Hash) -> Fallible<Content> {
+        debug!("fetching {:?} from peers", hash);
+        let (tx, rx) = std_mpsc::channel();
+        self.control_tx
+            .send(Control::Fetch(hash.clone(), Waiter::Sync(tx)))
+            .map_err(|_| err_msg("replication task has stopped"))?;
+        match rx.recv_timeout(self.fetch_timeout) {
+            Ok(Some(content)) => Ok(content),
+            Ok(None) => bail!("No object found"),
+            Err(_) => bail!("Timed out fetching object {:?}", hash),
+        }
+    }
+
+    /// Fetch a missing object from peers, asynchronously.
+    async fn fetch_async(&self, hash: &Hash) -> Fallible<Content> {
+        debug!("fetching {:?} from peers", hash);
+        let (tx, rx) = oneshot::channel();
+        self.control_tx
+            .send(Control::Fetch(hash.clone(), Waiter::Async(tx)))
+            .map_err(|_| err_msg("replication task has stopped"))?;
+        match timeout(self.fetch_timeout, rx).await {
+            Ok(Ok(Some(content))) => Ok(content),
+            Ok(Ok(None)) => bail!("No object found"),
+            Ok(Err(_)) => bail!("replication task has stopped"),
+            Err(_) => bail!("Timed out fetching object {:?}", hash),
+        }
+    }
+
+    /// Stop replicating.
+    pub async fn stop(self) {
+        // if the send fails, the task has already exited
+        let _ = self.control_tx.send(Control::Stop);
+        self.task.await.unwrap();
+    }
+}
+
+impl<ST> fmt::Debug for ReplicatedStorage<ST>
+where
+    ST: CAS + Send + Sync + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplicatedStorage")
+            .field("local", &self.local)
+            .finish()
+    }
+}
+
+impl<ST> CAS for ReplicatedStorage<ST>
+where
+    ST: CAS + Send + Sync + 'static,
+{
+    fn store(&self, value: Content) -> Fallible<Hash> {
+        let hash = self.local.store(value)?;
+        self.control_tx
+            .send(Control::Advertise(hash.clone()))
+            .map_err(|_| err_msg("replication task has stopped"))?;
+        Ok(hash)
+    }
+
+    fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        match self.local.retrieve(hash) {
+            Ok(content) => Ok(content),
+            Err(_) => self.fetch(hash),
+        }
+    }
+
+    fn contains(&self, hash: &Hash) -> Fallible<bool> {
+        // only objects already replicated to this node are considered
+        self.local.contains(hash)
+    }
+
+    fn resolve_prefix(&self, prefix: &str) -> Fallible<Hash> {
+        // only objects already replicated to this node are considered
+        self.local.resolve_prefix(prefix)
+    }
+
+    fn objects(&self) -> Fallible<Objects> {
+        // only objects already replicated to this node are considered
+        self.local.objects()
+    }
+
+    fn stats(&self) -> Fallible<Stats> {
+        self.local.stats()
+    }
+
+    fn touch(&self, hash: &Hash) -> Fallible<()> {
+        match self.local.touch(hash) {
+            Ok(()) => Ok(()),
+            // a fetched object is stored in the current generation, so there is no need to
+            // touch it again
+            Err(_) => self.fetch(hash).map(|_| ()),
+        }
+    }
+
+    fn pin(&self, hash: &Hash) -> Fallible<()> {
+        // a pinned object must be present locally, so fetch it if necessary
+        if !self.local.contains(hash)? {
+            self.fetch(hash)?;
+        }
+        self.local.pin(hash)
+    }
+
+    fn unpin(&self, hash: &Hash) -> Fallible<()> {
+        self.local.unpin(hash)
+    }
+
+    fn begin_gc(&self) -> Fallible<()> {
+        self.local.begin_gc()
+    }
+
+    fn end_gc(&self) {
+        self.local.end_gc()
+    }
+
+    fn abort_gc(&self) {
+        self.local.abort_gc()
+    }
+}
+
+// note that AsyncCAS is not imported into this module, as its methods would then be ambiguous with
+// those of CAS
+#[async_trait]
+impl<ST> cas::AsyncCAS for ReplicatedStorage<ST>
+where
+    ST: CAS + Send + Sync + 'static,
+{
+    async fn store(&self, value: Content) -> Fallible<Hash> {
+        CAS::store(self, value)
+    }
+
+    async fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        match self.local.retrieve(hash) {
+            Ok(content) => Ok(content),
+            Err(_) => self.fetch_async(hash).await,
+        }
+    }
+
+    async fn touch(&self, hash: &Hash) -> Fallible<()> {
+        match self.local.touch(hash) {
+            Ok(()) => Ok(()),
+            Err(_) => self.fetch_async(hash).await.map(|_| ()),
+        }
+    }
+
+    async fn begin_gc(&self) -> Fallible<()> {
+        self.local.begin_gc()
+    }
+
+    async fn end_gc(&self) {
+        self.local.end_gc()
+    }
+
+    async fn abort_gc(&self) {
+        self.local.abort_gc()
+    }
+}
+
+/// The background task for a ReplicatedStorage.  This owns the network node, and handles both
+/// control messages and messages from peers.
+struct ReplicationInner<NODE, ST>
+where
+    NODE: NetworkNode + Sync + Send + 'static,
+    ST: CAS + Send + Sync + 'static,
+{
+    /// The network node, used for communication
+    node: NODE,
+
+    /// The local storage pool
+    local: Arc<ST>,
+
+    /// Channel containing control messages from the ReplicatedStorage
+    control_rx: mpsc::UnboundedReceiver<Control>,
+
+    /// Fetches that are waiting for an object from peers
+    fetches: HashMap<Hash, Fetch>,
+
+    /// Time to wait for peers to supply a missing object
+    fetch_timeout: Duration,
+
+    /// The request id for the next fetch's `Want`.  Id 0 is used for other requests.
+    next_request: u32,
+}
+
+/// An in-progress fetch of a missing object
+struct Fetch {
+    /// Time at which `Want` was sent to peers
+    started: Instant,
+
+    /// The request id of the `Want`; replies to earlier requests for the same object are ignored
+    request: u32,
+
+    /// Peers that have not yet responded to the `Want`
+    outstanding: HashSet<NodeId>,
+
+    /// Callers waiting for the result, with the time at which each began waiting
+    waiters: Vec<(Instant, Waiter)>,
+}
+
+impl<NODE, ST> ReplicationInner<NODE, ST>
+where
+    NODE: NetworkNode + Sync + Send + 'static,
+    ST: CAS + Send + Sync + 'static,
+{
+    async fn run(mut self) {
+        loop {
+            let res = tokio::select! {
+                c = self.control_rx.recv() => match c {
+                    Some(Control::SetFetchTimeout(fetch_timeout)) => {
+                        self.fetch_timeout = fetch_timeout;
+                        Ok(())
+                    }
+                    Some(Control::Advertise(hash)) => self.advertise(None, hash).await,
+                    Some(Control::Fetch(hash, waiter)) => self.start_fetch(hash, waiter).await,
+                    // the ReplicatedStorage is stopping or has been dropped
+                    Some(Control::Stop) | None => return,
+                },
+                Ok((peer, msg)) = self.node.recv() => self.handle_message(peer, msg).await,
+            };
+            if let Err(e) = res {
+                warn!("replication error: {}", e);
+            }
+        }
+    }
+
+    /// Run an operation on the local storage pool on a thread where blocking is acceptable, so
+    /// that a slow storage pool does not block the Tokio runtime
+    async fn with_local<F, T>(&self, f: F) -> Fallible<T>
+    where
+        F: FnOnce(&ST) -> Fallible<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let local = self.local.clone();
+        task::spawn_blocking(move || f(&local)).await?
+    }
+
+    /// Send a message to every peer except `except`
+    async fn broadcast(&mut self, except: Option<NodeId>, message: Message) -> Fallible<()> {
+        let msg = message.serialize();
+        let node_id = self.node.node_id();
+        for peer in 0..self.node.network_size() {
+            if peer != node_id && Some(peer) != except {
+                self.node.send(peer, msg.clone()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Advertise a hash to all peers (except `except`, which is known to have it)
+    async fn advertise(&mut self, except: Option<NodeId>, hash: Hash) -> Fallible<()> {
+        debug!("advertising {:?}", hash);
+        self.broadcast(except, Message::Have(vec![hash])).await
+    }
+
+    /// Begin fetching an object from peers, or join an existing fetch for the same object.
+    async fn start_fetch(&mut self, hash: Hash, waiter: Waiter) -> Fallible<()> {
+        // the object may have arrived since the caller looked for it
+        let h = hash.clone();
+        if let Some(content) = self
+            .with_local(move |local| Ok(local.retrieve(&h).ok()))
+            .await?
+        {
+            waiter.send(Some(content));
+            return Ok(());
+        }
+
+        self.prune_fetches();
+        if let Some(fetch) = self.fetches.get_mut(&hash) {
+            // if the existing fetch has been going for a while, some messages may have been
+            // lost; start over, keeping the existing waiters
+            if fetch.started.elapsed() < self.fetch_timeout {
+                fetch.waiters.push((Instant::now(), waiter));
+                return Ok(());
+            }
+        }
+
+        let mut waiters = self
+            .fetches
+            .remove(&hash)
+            .map(|f| f.waiters)
+            .unwrap_or_default();
+        waiters.push((Instant::now(), waiter));
+
+        let node_id = self.node.node_id();
+        let outstanding: HashSet<NodeId> = (0..self.node.network_size())
+            .filter(|peer| *peer != node_id)
+            .collect();
+        if outstanding.is_empty() {
+            for (_, waiter) in waiters {
+                waiter.send(None);
+            }
+            return Ok(());
+        }
+
+        let request = self.next_request;
+        self.next_request = self.next_request.checked_add(1).unwrap_or(1);
+        self.fetches.insert(
+            hash.clone(),
+            Fetch {
+                started: Instant::now(),
+                request,
+                outstanding,
+                waiters,
+            },
+        );
+        self.broadcast(None, Message::Want(request, vec![hash]))
+            .await
+    }
+
+    /// Forget waiters that have given up, and fetches that no longer have any waiters, so that
+    /// fetches for objects no peer ever supplies do not accumulate
+    fn prune_fetches(&mut self) {
+        let fetch_timeout = self.fetch_timeout;
+        self.fetches.retain(|hash, fetch| {
+            fetch
+                .waiters
+                .retain(|(since, waiter)| !waiter.is_gone(*since, fetch_timeout));
+            if fetch.waiters.is_empty() {
+                debug!("abandoning fetch of {:?}", hash);
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Complete a fetch (if one is in progress) with the given result
+    fn finish_fetch(&mut self, hash: &Hash, content: Option<&Content>) {
+        if let Some(fetch) = self.fetches.remove(hash) {
+            for (_, waiter) in fetch.waiters {
+                waiter.send(content.cloned());
+            }
+        }
+    }
+
+    async fn handle_message(&mut self, peer: NodeId, msg: Vec<u8>) -> Fallible<()> {
+        match Message::deserialize(&msg)? {
+            Message::Have(hashes) => {
+                let wanted: Vec<Hash> = self
+                    .with_local(move |local| {
+                        Ok(hashes
+                            .into_iter()
+                            .filter(|h| !local.contains(h).unwrap_or(false))
+                            .collect())
+                    })
+                    .await?;
+                if !wanted.is_empty() {
+                    debug!("requesting {:?} from {}", wanted, peer);
+                    self.node
+                        .send(peer, Message::Want(0, wanted).serialize())
+                        .await?;
+                }
+            }
+            Message::Want(request, hashes) => {
+                let (found, missing) = self
+                    .with_local(move |local| {
+                        let mut found = vec![];
+                        let mut missing = vec![];
+                        for hash in hashes {
+                            match local.retrieve(&hash) {
+                                Ok(content) => found.push((hash, content)),
+                                Err(_) => missing.push(hash),
+                            }
+                        }
+                        Ok((found, missing))
+                    })
+                    .await?;
+                for (hash, content) in found {
+                    self.node
+                        .send(peer, Message::Object(hash, content).serialize())
+                        .await?;
+                }
+                if !missing.is_empty() {
+                    self.node
+                        .send(peer, Message::Missing(request, missing).serialize())
+                        .await?;
+                }
+            }
+            Message::Object(hash, content) => {
+                if !hash.verify(&content) {
+                    warn!(
+                        "discarding object from {} that does not match {:?}",
+                        peer, hash
+                    );
+                    return Ok(());
+                }
+                let (h, c) = (hash.clone(), content.clone());
+                let stored = self
+                    .with_local(move |local| {
+                        if local.contains(&h)? {
+                            return Ok(None);
+                        }
+                        local.store(c).map(Some)
+                    })
+                    .await?;
+                if let Some(stored) = stored {
+                    debug!("received {:?} from {}", hash, peer);
+                    if stored != hash {
+                        // the local storage uses a different hash algorithm than the peer
+                        warn!("{:?} from {} was stored under a different hash", hash, peer);
+                    }
+                    self.finish_fetch(&hash, Some(&content));
+                    self.advertise(Some(peer), hash).await?;
+                } else {
+                    self.finish_fetch(&hash, Some(&content));
+                }
+            }
+            Message::Missing(request, hashes) => {
+                for hash in hashes {
+                    let done = match self.fetches.get_mut(&hash) {
+                        // a reply to an earlier request, or a repeated reply, does not count
+                        Some(fetch) if fetch.request == request => {
+                            fetch.outstanding.remove(&peer) && fetch.outstanding.is_empty()
+                        }
+                        _ => false,
+                    };
+                    if done {
+                        debug!("no peer has {:?}", hash);
+                        self.finish_fetch(&hash, None);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cas::LocalStorage;
+    use crate::net::local::LocalNetwork;
+    use std::time::Duration;
+    use tokio::time::delay_for;
+
+    /// Wait until the given hash has been replicated to the given storage
+    async fn wait_for<ST>(storage: &ReplicatedStorage<ST>, hash: &Hash) -> Content
+    where
+        ST: CAS + Send + Sync + 'static,
+    {
+        for _ in 0..100 {
+            // check the local storage, to avoid blocking on a fetch
+            if let Ok(content) = storage.local.retrieve(hash) {
+                return content;
+            }
+            delay_for(Duration::from_millis(10)).await;
+        }
+        panic!("{:?} never arrived", hash);
+    }
+
+    #[tokio::test]
+    async fn replicates_to_all_nodes() -> Fallible<()> {
+        let mut net = LocalNetwork::new(3);
+        let storages: Vec<_> = (0..3)
+            .map(|i| ReplicatedStorage::new(net.take(i), LocalStorage::new()))
+            .collect();
+
+        let hash = storages[0].store(b"hello".to_vec())?;
+        assert_eq!(wait_for(&storages[1], &hash).await, b"hello".to_vec());
+        assert_eq!(wait_for(&storages[2], &hash).await, b"hello".to_vec());
+
+        let hash = storages[2].store(b"world".to_vec())?;
+        assert_eq!(wait_for(&storages[0], &hash).await, b"world".to_vec());
+
+        for storage in storages {
+            storage.stop().await;
+        }
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn filesystem_commit_replicates() -> Fallible<()> {
+        use crate::fs::{Commit, FileSystem, Tree};
+
+        let mut net = LocalNetwork::new(2);
+        let fs0 = FileSystem::new(Box::new(ReplicatedStorage::new(
+            net.take(0),
+            LocalStorage::new(),
+        )));
+        let storage1 = ReplicatedStorage::new(net.take(1), LocalStorage::new());
+
+        let tree = Tree::empty().write(&fs0, &["x"], vec![7])?;
+        let cmt = Commit::root(&fs0)?.make_child(&fs0, &tree)?;
+        let hash = cmt.hash(&fs0)?.clone();
+
+        // wait for the commit, then read it on the other node; any objects that have not yet
+        // been replicated are fetched on demand
+        wait_for(&storage1, &hash).await;
+        let fs1 = FileSystem::new(Box::new(storage1));
+        task::block_in_place(|| {
+            let tree = Commit::for_hash(&hash).tree(&fs1)?;
+            assert_eq!(tree.read(&fs1, &["x"])?, Some(vec![7]));
+            Ok(())
+        })
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn fetch_on_miss() -> Fallible<()> {
+        // node 0 has content before replication starts, so it is never advertised
+        let mut net = LocalNetwork::new(3);
+        let local0 = LocalStorage::new();
+        let hash = local0.store(b"old".to_vec())?;
+        let storage0 = ReplicatedStorage::new(net.take(0), local0);
+        let storage1 = Arc::new(ReplicatedStorage::new(net.take(1), LocalStorage::new()));
+        let storage2 = ReplicatedStorage::new(net.take(2), LocalStorage::new());
+
+        let s1 = storage1.clone();
+        let h = hash.clone();
+        let content = task::spawn_blocking(move || s1.retrieve(&h)).await??;
+        assert_eq!(content, b"old".to_vec());
+
+        // the fetched object is now cached locally (and advertised to node 2)
+        assert_eq!(storage1.local.retrieve(&hash)?, b"old".to_vec());
+
+        // touch fetches, too
+        let h = hash.clone();
+        let storage2 = Arc::new(storage2);
+        let s2 = storage2.clone();
+        task::spawn_blocking(move || s2.touch(&h)).await??;
+        assert!(storage2.local.retrieve(&hash).is_ok());
+
+        storage0.stop().await;
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn fetch_nonexistent_fails() -> Fallible<()> {
+        let mut net = LocalNetwork::new(3);
+        let storage = Arc::new(
+            ReplicatedStorage::new(net.take(0), LocalStorage::new())
+                // long enough that the test would fail if we waited for the timeout
+                .with_fetch_timeout(Duration::from_secs(30)),
+        );
+        let _storage1 = ReplicatedStorage::new(net.take(1), LocalStorage::new());
+        let _storage2 = ReplicatedStorage::new(net.take(2), LocalStorage::new());
+
+        let s = storage.clone();
+        let start = Instant::now();
+        let res = task::spawn_blocking(move || s.retrieve(&Hash::from_hex("0123"))).await?;
+        assert!(res.is_err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let s = storage.clone();
+        assert!(
+            task::spawn_blocking(move || s.touch(&Hash::from_hex("0123")))
+                .await?
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn async_fetch_on_miss() -> Fallible<()> {
+        // unlike the synchronous methods, this works without blocking the (single-threaded)
+        // runtime
+        let mut net = LocalNetwork::new(2);
+        let local0 = LocalStorage::new();
+        let hash = local0.store(b"old".to_vec())?;
+        let storage0 = ReplicatedStorage::new(net.take(0), local0);
+        let storage1 = ReplicatedStorage::new(net.take(1), LocalStorage::new());
+
+        assert_eq!(
+            cas::AsyncCAS::retrieve(&storage1, &hash).await?,
+            b"old".to_vec()
+        );
+        assert!(storage1.local.retrieve(&hash).is_ok());
+        assert!(cas::AsyncCAS::touch(&storage1, &Hash::from_hex("0123"))
+            .await
+            .is_err());
+
+        storage0.stop().await;
+        storage1.stop().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restarts_stale_fetch() -> Fallible<()> {
+        let mut net = LocalNetwork::new(2);
+        let storage = ReplicatedStorage::new(net.take(0), LocalStorage::new())
+            .with_fetch_timeout(Duration::from_millis(100));
+        // node 1 never answers
+        let mut node1 = net.take(1);
+        let hash = Hash::from_hex("0123");
+
+        // each fetch outlives the previous one's timeout, so sends its own Want
+        let mut requests = vec![];
+        for _ in 0..2 {
+            assert!(cas::AsyncCAS::retrieve(&storage, &hash).await.is_err());
+            let (_, msg) = timeout(Duration::from_secs(10), node1.recv()).await??;
+            match Message::deserialize(&msg)? {
+                Message::Want(request, hashes) => {
+                    assert_eq!(hashes, vec![hash.clone()]);
+                    requests.push(request);
+                }
+                m => panic!("unexpected {:?}", m),
+            }
+        }
+        assert_ne!(requests[0], requests[1]);
+
+        storage.stop().await;
+        Ok(())
+    }
+
+    /// Receive a Want message, returning its request id
+    async fn recv_want<NODE: NetworkNode>(node: &mut NODE) -> Fallible<u32> {
+        let (_, msg) = timeout(Duration::from_secs(10), node.recv()).await??;
+        match Message::deserialize(&msg)? {
+            Message::Want(request, _) => Ok(request),
+            m => panic!("unexpected {:?}", m),
+        }
+    }
+
+    #[tokio::test]
+    async fn ignores_stale_missing() -> Fallible<()> {
+        let mut net = LocalNetwork::new(2);
+        let storage = Arc::new(
+            ReplicatedStorage::new(net.take(0), LocalStorage::new())
+                .with_fetch_timeout(Duration::from_millis(300)),
+        );
+        let mut node1 = net.take(1);
+        let content = b"late".to_vec();
+        let hash = Hash::for_bytes(&content);
+
+        // the first fetch times out, and a second fetch restarts it
+        assert!(cas::AsyncCAS::retrieve(&*storage, &hash).await.is_err());
+        let first = recv_want(&mut node1).await?;
+        let s = storage.clone();
+        let h = hash.clone();
+        let second = tokio::spawn(async move { cas::AsyncCAS::retrieve(&*s, &h).await });
+        recv_want(&mut node1).await?;
+
+        // a late reply to the first Want does not end the second fetch
+        node1
+            .send(0, Message::Missing(first, vec![hash.clone()]).serialize())
+            .await?;
+        node1
+            .send(
+                0,
+                Message::Object(hash.clone(), content.clone()).serialize(),
+            )
+            .await?;
+        assert_eq!(second.await??, content);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prunes_abandoned_fetches() {
+        let mut net = LocalNetwork::new(2);
+        let (_control_tx, control_rx) = mpsc::unbounded_channel();
+        let mut inner = ReplicationInner {
+            node: net.take(0),
+            local: Arc::new(LocalStorage::new()),
+            control_rx,
+            fetches: HashMap::new(),
+            fetch_timeout: Duration::from_secs(30),
+            next_request: 1,
+        };
+        let fetch = |since, waiter| Fetch {
+            started: Instant::now(),
+            request: 1,
+            outstanding: vec![1].into_iter().collect(),
+            waiters: vec![(since, waiter)],
+        };
+
+        // an async caller that has gone away
+        let (tx, rx) = oneshot::channel();
+        drop(rx);
+        inner.fetches.insert(
+            Hash::from_hex("01"),
+            fetch(Instant::now(), Waiter::Async(tx)),
+        );
+
+        // a sync caller that has timed out
+        let (tx, _rx) = std_mpsc::channel();
+        inner.fetches.insert(
+            Hash::from_hex("02"),
+            fetch(Instant::now() - Duration::from_secs(60), Waiter::Sync(tx)),
+        );
+
+        // callers that are still waiting
+        let (tx, _rx1) = oneshot::channel();
+        inner.fetches.insert(
+            Hash::from_hex("03"),
+            fetch(Instant::now(), Waiter::Async(tx)),
+        );
+        let (tx, _rx2) = std_mpsc::channel();
+        inner.fetches.insert(
+            Hash::from_hex("04"),
+            fetch(Instant::now(), Waiter::Sync(tx)),
+        );
+
+        inner.prune_fetches();
+        let mut remaining: Vec<_> = inner.fetches.keys().cloned().collect();
+        remaining.sort();
+        assert_eq!(remaining, vec![Hash::from_hex("03"), Hash::from_hex("04")]);
+    }
+
+    #[tokio::test]
+    async fn ignores_bad_objects() -> Fallible<()> {
+        let mut net = LocalNetwork::new(2);
+        let storage = ReplicatedStorage::new(net.take(0), LocalStorage::new());
+        let mut node1 = net.take(1);
+
+        let hash = Hash::for_bytes(&b"real".to_vec());
+        node1
+            .send(
+                0,
+                Message::Object(hash.clone(), b"fake".to_vec()).serialize(),
+            )
+            .await?;
+        node1
+            .send(0, Message::Have(vec![Hash::from_hex("0123")]).serialize())
+            .await?;
+
+        // the bogus object was not stored, and the Have elicited a Want
+        let (_, msg) = node1.recv().await?;
+        assert_eq!(
+            Message::deserialize(&msg)?,
+            Message::Want(0, vec![Hash::from_hex("0123")])
+        );
+        assert!(storage.local.retrieve(&hash).is_err());
+
+        storage.stop().await;
+        Ok(())
+    }
+}
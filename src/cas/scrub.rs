@@ -0,0 +1,92 @@
+use super::hash::Hash;
+use super::traits::CAS;
+use failure::Fallible;
+use log::{debug, warn};
+
+/// The result of scrubbing a storage pool
+#[derive(Debug, Default, PartialEq)]
+pub struct ScrubReport {
+    /// The number of objects checked
+    pub checked: usize,
+
+    /// Objects whose content does not match their hash
+    pub corrupt: Vec<Hash>,
+
+    /// Objects which are listed in the storage pool but could not be retrieved
+    pub unreadable: Vec<Hash>,
+}
+
+impl ScrubReport {
+    /// Return true if no problems were found
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty() && self.unreadable.is_empty()
+    }
+}
+
+/// Check the integrity of every object in the given storage pool, by re-hashing its content with
+/// the algorithm that generated its hash and comparing the result to that hash.
+///
+/// Objects collected as garbage while the scrub is running will be reported as unreadable, so
+/// this is best run between garbage-collection cycles.
+pub fn scrub<ST: CAS + ?Sized>(storage: &ST) -> Fallible<ScrubReport> {
+    let mut report = ScrubReport::default();
+    for object in storage.objects()? {
+        let hash = object.hash;
+        report.checked += 1;
+        match storage.retrieve(&hash) {
+            Ok(content) => {
+                if !hash.verify(&content) {
+                    warn!("object {:?} does not match its hash", hash);
+                    report.corrupt.push(hash);
+                }
+            }
+            Err(e) => {
+                warn!("could not retrieve object {:?}: {}", hash, e);
+                report.unreadable.push(hash);
+            }
+        }
+    }
+    debug!("scrub complete: {:?}", report);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::{Algorithm, DiskStorage, Storage};
+    use crate::util::test::TempDir;
+    use std::fs;
+
+    #[test]
+    fn clean() {
+        let storage = Storage::with_algorithm(Algorithm::Blake3);
+        storage.store(b"abc".to_vec()).unwrap();
+        storage.store(b"def".to_vec()).unwrap();
+
+        let report = scrub(&storage).unwrap();
+        assert_eq!(report.checked, 2);
+        assert!(report.is_clean());
+    }
+
+    #[test]
+    fn corrupt_on_disk() {
+        let dir = TempDir::new();
+        let storage = DiskStorage::new(dir.path()).unwrap();
+        let good = storage.store(b"good".to_vec()).unwrap();
+        let bad = storage.store(b"bad".to_vec()).unwrap();
+        let gone = storage.store(b"gone".to_vec()).unwrap();
+
+        let object_path = |hash: &Hash| {
+            let hex = hash.to_hex();
+            dir.path().join("objects").join(&hex[..2]).join(hex)
+        };
+        fs::write(object_path(&bad), b"flipped").unwrap();
+        fs::remove_file(object_path(&gone)).unwrap();
+
+        let report = scrub(&storage).unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.corrupt, vec![bad]);
+        assert_eq!(report.unreadable, vec![gone]);
+        assert!(storage.retrieve(&good).is_ok());
+    }
+}
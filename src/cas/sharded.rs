@@ -0,0 +1,425 @@
+use super::async_cas::AsyncCAS;
+use super::hash::{Algorithm, Hash};
+use super::pin::Pins;
+use super::prefix::{matching_prefix, unique_match};
+use super::stats::{GcTimer, Stats};
+use super::traits::{Content, ObjectInfo, Objects, StoreAs, CAS};
+use async_trait::async_trait;
+use failure::{bail, err_msg, Fallible};
+use log::debug;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+
+/// The number of shards; each shard holds the objects with one value of the first byte of the
+/// hash digest.
+const SHARDS: usize = 256;
+
+/// Type ShardedStorage provides an in-memory content-addressible storage pool with the same
+/// semantics as `Storage`, but suited to heavy concurrent use.
+///
+/// Objects are partitioned into shards by hash prefix, each with its own lock, so operations on
+/// different objects rarely contend.  Each object's generation is stored in an atomic, so `touch`
+/// and re-storing an existing object need only a read lock on the shard.
+pub struct ShardedStorage {
+    algorithm: Algorithm,
+    shards: Vec<RwLock<BTreeMap<Hash, Entry>>>,
+    garbage_generation: AtomicU64,
+    cur_generation: AtomicU64,
+    pins: Mutex<Pins>,
+    gc_timer: Mutex<GcTimer>,
+}
+
+struct Entry {
+    generation: AtomicU64,
+    value: Content,
+}
+
+impl Entry {
+    /// Mark this entry as part of the given generation, unless it is already in a later one
+    fn touch(&self, generation: u64) {
+        self.generation.fetch_max(generation, Ordering::SeqCst);
+    }
+}
+
+impl fmt::Debug for ShardedStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedStorage")
+            .field("algorithm", &self.algorithm)
+            .field("garbage_generation", &self.garbage_generation)
+            .field("cur_generation", &self.cur_generation)
+            .finish()
+    }
+}
+
+impl ShardedStorage {
+    /// Create a new, empty storage pool.
+    pub fn new() -> ShardedStorage {
+        ShardedStorage::with_algorithm(Algorithm::default())
+    }
+
+    /// Create a new, empty storage pool which hashes newly-stored content with the given
+    /// algorithm.
+    pub fn with_algorithm(algorithm: Algorithm) -> ShardedStorage {
+        ShardedStorage {
+            algorithm,
+            shards: (0..SHARDS).map(|_| RwLock::new(BTreeMap::new())).collect(),
+            garbage_generation: AtomicU64::new(0),
+            cur_generation: AtomicU64::new(1),
+            pins: Mutex::new(Pins::default()),
+            gc_timer: Mutex::new(GcTimer::default()),
+        }
+    }
+
+    fn shard(&self, hash: &Hash) -> &RwLock<BTreeMap<Hash, Entry>> {
+        let prefix = hash.digest().first().cloned().unwrap_or(0);
+        &self.shards[prefix as usize % SHARDS]
+    }
+}
+
+impl Default for ShardedStorage {
+    fn default() -> ShardedStorage {
+        ShardedStorage::new()
+    }
+}
+
+impl StoreAs for ShardedStorage {
+    fn store_as(&self, hash: &Hash, value: Content) -> Fallible<()> {
+        debug!("store content with hash {:?}", hash);
+        let cur_generation = self.cur_generation.load(Ordering::SeqCst);
+        let shard = self.shard(hash);
+
+        // content is frequently stored again, and that requires only a read lock
+        {
+            let map = shard.read().map_err(|_| err_msg("Lock Poisoned"))?;
+            if let Some(entry) = map.get(hash) {
+                entry.touch(cur_generation);
+                return Ok(());
+            }
+        }
+
+        let mut map = shard.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        map.entry(hash.clone())
+            .or_insert_with(|| Entry {
+                generation: AtomicU64::new(cur_generation),
+                value,
+            })
+            .touch(cur_generation);
+        Ok(())
+    }
+}
+
+impl CAS for ShardedStorage {
+    fn store(&self, value: Content) -> Fallible<Hash> {
+        let hash = self.algorithm.hash(&value);
+        self.store_as(&hash, value)?;
+        Ok(hash)
+    }
+
+    fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        debug!("retrieve content with hash {:?}", hash);
+        let map = self
+            .shard(hash)
+            .read()
+            .map_err(|_| err_msg("Lock Poisoned"))?;
+        match map.get(hash) {
+            None => bail!("No object found"),
+            Some(entry) => Ok(entry.value.clone()),
+        }
+    }
+
+    fn contains(&self, hash: &Hash) -> Fallible<bool> {
+        let map = self
+            .shard(hash)
+            .read()
+            .map_err(|_| err_msg("Lock Poisoned"))?;
+        Ok(map.contains_key(hash))
+    }
+
+    fn resolve_prefix(&self, prefix: &str) -> Fallible<Hash> {
+        let prefix = prefix.to_lowercase();
+        let mut candidates = vec![];
+        for shard in self.shards.iter() {
+            let map = shard.read().map_err(|_| err_msg("Lock Poisoned"))?;
+            candidates.extend(matching_prefix(&map, &prefix)?);
+        }
+        candidates.sort();
+        unique_match(prefix, candidates)
+    }
+
+    fn objects(&self) -> Fallible<Objects> {
+        // lock one shard at a time
+        let mut objects = vec![];
+        for shard in self.shards.iter() {
+            let map = shard.read().map_err(|_| err_msg("Lock Poisoned"))?;
+            objects.extend(map.iter().map(|(hash, entry)| ObjectInfo {
+                hash: hash.clone(),
+                size: entry.value.len() as u64,
+                generation: entry.generation.load(Ordering::SeqCst),
+            }));
+        }
+        Ok(Box::new(objects.into_iter()))
+    }
+
+    fn touch(&self, hash: &Hash) -> Fallible<()> {
+        debug!("touch content with hash {:?}", hash);
+        let cur_generation = self.cur_generation.load(Ordering::SeqCst);
+        let map = self
+            .shard(hash)
+            .read()
+            .map_err(|_| err_msg("Lock Poisoned"))?;
+        match map.get(hash) {
+            None => bail!("No object found"),
+            Some(entry) => {
+                entry.touch(cur_generation);
+                Ok(())
+            }
+        }
+    }
+
+    fn stats(&self) -> Fallible<Stats> {
+        let mut objects = vec![];
+        for shard in self.shards.iter() {
+            let map = shard.read().map_err(|_| err_msg("Lock Poisoned"))?;
+            objects.extend(map.values().map(|entry| {
+                (
+                    entry.generation.load(Ordering::SeqCst),
+                    entry.value.len() as u64,
+                )
+            }));
+        }
+        let mut stats = Stats::tally(
+            objects.into_iter(),
+            self.cur_generation.load(Ordering::SeqCst),
+            self.garbage_generation.load(Ordering::SeqCst),
+        );
+        stats.last_gc = self
+            .gc_timer
+            .lock()
+            .map_err(|_| err_msg("Lock Poisoned"))?
+            .last_gc();
+        Ok(stats)
+    }
+
+    fn pin(&self, hash: &Hash) -> Fallible<()> {
+        // hold the shard lock while pinning, so that end_gc cannot remove the object in between
+        let map = self
+            .shard(hash)
+            .read()
+            .map_err(|_| err_msg("Lock Poisoned"))?;
+        if !map.contains_key(hash) {
+            bail!("No object found");
+        }
+        let mut pins = self.pins.lock().map_err(|_| err_msg("Lock Poisoned"))?;
+        pins.pin(hash);
+        Ok(())
+    }
+
+    fn unpin(&self, hash: &Hash) -> Fallible<()> {
+        let mut pins = self.pins.lock().map_err(|_| err_msg("Lock Poisoned"))?;
+        pins.unpin(hash)
+    }
+
+    fn begin_gc(&self) -> Fallible<()> {
+        self.gc_timer
+            .lock()
+            .map_err(|_| err_msg("Lock Poisoned"))?
+            .begin();
+        let cur_generation = self.cur_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        debug!("begin_gc: cur_generation={}", cur_generation);
+        Ok(())
+    }
+
+    fn abort_gc(&self) {
+        // objects that were not touched are now in the garbage generation, so they are collected
+        // when the next cycle ends
+        let garbage_generation = self.garbage_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        debug!("abort_gc: garbage_generation={}", garbage_generation);
+        if let Ok(mut gc_timer) = self.gc_timer.lock() {
+            gc_timer.abort();
+        }
+    }
+
+    fn end_gc(&self) {
+        let garbage_generation = self.garbage_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        debug!("end_gc: garbage_generation={}", garbage_generation);
+        let cur_generation = self.cur_generation.load(Ordering::SeqCst);
+
+        let mut objects_reclaimed = 0;
+        let mut bytes_reclaimed = 0;
+        for shard in self.shards.iter() {
+            // locking fails only with a PoisonError; see Storage::end_gc
+            let mut map = match shard.write() {
+                Ok(map) => map,
+                Err(_) => continue,
+            };
+            let pins = match self.pins.lock() {
+                Ok(pins) => pins,
+                Err(_) => return,
+            };
+
+            // pinned objects are carried forward into the current generation
+            let old_map = mem::take(&mut *map);
+            let (new_map, garbage): (BTreeMap<_, _>, BTreeMap<_, _>) =
+                old_map.into_iter().partition(|(hash, entry)| {
+                    if pins.is_pinned(hash) {
+                        entry.touch(cur_generation);
+                    }
+                    entry.generation.load(Ordering::SeqCst) > garbage_generation
+                });
+            *map = new_map;
+            objects_reclaimed += garbage.len();
+            bytes_reclaimed += garbage
+                .values()
+                .map(|entry| entry.value.len() as u64)
+                .sum::<u64>();
+        }
+
+        if let Ok(mut gc_timer) = self.gc_timer.lock() {
+            gc_timer.end(objects_reclaimed, bytes_reclaimed);
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncCAS for ShardedStorage {
+    // these operations do no I/O and hold a std lock only briefly, on one shard at a time (end_gc
+    // sweeps the shards in turn), so they run directly in the async context
+    async fn store(&self, value: Content) -> Fallible<Hash> {
+        CAS::store(self, value)
+    }
+
+    async fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        CAS::retrieve(self, hash)
+    }
+
+    async fn touch(&self, hash: &Hash) -> Fallible<()> {
+        CAS::touch(self, hash)
+    }
+
+    async fn begin_gc(&self) -> Fallible<()> {
+        CAS::begin_gc(self)
+    }
+
+    async fn end_gc(&self) {
+        CAS::end_gc(self)
+    }
+
+    async fn abort_gc(&self) {
+        CAS::abort_gc(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShardedStorage;
+    use crate::cas::hash::{Algorithm, Hash};
+    use crate::cas::traits::CAS;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn simple_put_get_strings() {
+        let storage = ShardedStorage::new();
+
+        let hash1 = storage.store(b"one".to_vec()).unwrap();
+        let hash2 = storage.store(b"two".to_vec()).unwrap();
+        let badhash = Hash::from_hex("000000");
+
+        assert_eq!(storage.retrieve(&hash1).unwrap(), b"one".to_vec());
+        assert_eq!(storage.retrieve(&hash2).unwrap(), b"two".to_vec());
+        assert!(storage.retrieve(&badhash).is_err());
+        assert!(storage.contains(&hash1).unwrap());
+        assert!(!storage.contains(&badhash).unwrap());
+    }
+
+    #[test]
+    fn with_algorithm() {
+        let storage = ShardedStorage::with_algorithm(Algorithm::Blake3);
+
+        let hash = storage.store(b"fast".to_vec()).unwrap();
+        assert_eq!(hash.algorithm(), Some(Algorithm::Blake3));
+        assert_eq!(storage.retrieve(&hash).unwrap(), b"fast".to_vec());
+    }
+
+    #[test]
+    fn resolve_prefix() {
+        let storage = ShardedStorage::new();
+
+        let hash = storage.store(b"one".to_vec()).unwrap();
+        storage.store(b"two".to_vec()).unwrap();
+        let prefix = &hash.to_hex()[..7];
+        assert_eq!(storage.resolve_prefix(prefix).unwrap(), hash);
+        assert!(storage.resolve_prefix("0000000").is_err());
+    }
+
+    #[test]
+    fn gc() {
+        let storage = ShardedStorage::new();
+
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        let hash2 = storage.store(b"def".to_vec()).unwrap();
+        let hash3 = storage.store(b"ghi".to_vec()).unwrap();
+
+        storage.begin_gc().unwrap();
+        storage.touch(&hash1).unwrap();
+        storage.store(b"ghi".to_vec()).unwrap(); // hash3
+        assert!(storage.touch(&Hash::from_hex("1234")).is_err());
+        storage.end_gc();
+
+        assert!(storage.retrieve(&hash1).is_ok()); // touched
+        assert!(storage.retrieve(&hash2).is_err()); // not referenced
+        assert!(storage.retrieve(&hash3).is_ok()); // stored
+
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.objects, 2);
+        assert_eq!(stats.bytes_per_generation.get(&2), Some(&6));
+        assert_eq!(stats.last_gc.unwrap().objects_reclaimed, 1);
+        assert_eq!(storage.objects().unwrap().count(), 2);
+    }
+
+    #[test]
+    fn pin() {
+        let storage = ShardedStorage::new();
+
+        let hash1 = storage.store(b"pinned".to_vec()).unwrap();
+        let hash2 = storage.store(b"unpinned".to_vec()).unwrap();
+        storage.pin(&hash1).unwrap();
+        assert!(storage.pin(&Hash::from_hex("1234")).is_err());
+
+        storage.begin_gc().unwrap();
+        storage.end_gc();
+        assert!(storage.contains(&hash1).unwrap());
+        assert!(!storage.contains(&hash2).unwrap());
+
+        storage.unpin(&hash1).unwrap();
+        storage.begin_gc().unwrap();
+        storage.end_gc();
+        assert!(!storage.contains(&hash1).unwrap());
+    }
+
+    #[test]
+    fn concurrent() {
+        let storage = Arc::new(ShardedStorage::new());
+
+        let threads: Vec<_> = (0..8u8)
+            .map(|t| {
+                let storage = storage.clone();
+                thread::spawn(move || {
+                    for i in 0..100u8 {
+                        let hash = storage.store(vec![t, i]).unwrap();
+                        storage.touch(&hash).unwrap();
+                        assert_eq!(storage.retrieve(&hash).unwrap(), vec![t, i]);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(storage.stats().unwrap().objects, 800);
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// A snapshot of the state of a storage pool, as returned by `CAS::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// The number of objects stored
+    pub objects: usize,
+
+    /// The total size of the stored objects, in bytes
+    pub bytes: u64,
+
+    /// The total size of the stored objects in each garbage-collection generation
+    pub bytes_per_generation: BTreeMap<u64, u64>,
+
+    /// The generation into which objects are currently stored or touched
+    pub cur_generation: u64,
+
+    /// The most recent generation to be collected as garbage
+    pub garbage_generation: u64,
+
+    /// Information about the most recently completed garbage-collection cycle, if any
+    pub last_gc: Option<GcStats>,
+
+    /// The number of objects evicted to stay within a byte budget, since the storage pool was
+    /// created
+    pub objects_evicted: u64,
+
+    /// The total size of the evicted objects, in bytes
+    pub bytes_evicted: u64,
+}
+
+/// Information about a completed garbage-collection cycle
+#[derive(Debug, Clone, PartialEq)]
+pub struct GcStats {
+    /// The number of objects reclaimed by `end_gc`
+    pub objects_reclaimed: usize,
+
+    /// The total size of the reclaimed objects, in bytes
+    pub bytes_reclaimed: u64,
+
+    /// The time from `begin_gc` to `end_gc`
+    pub duration: Duration,
+}
+
+impl Stats {
+    /// Calculate statistics for the given (generation, size) pairs, one per object.
+    pub(crate) fn tally<I>(objects: I, cur_generation: u64, garbage_generation: u64) -> Stats
+    where
+        I: Iterator<Item = (u64, u64)>,
+    {
+        let mut stats = Stats {
+            cur_generation,
+            garbage_generation,
+            ..Default::default()
+        };
+        for (generation, size) in objects {
+            stats.objects += 1;
+            stats.bytes += size;
+            *stats.bytes_per_generation.entry(generation).or_insert(0) += size;
+        }
+        stats
+    }
+}
+
+/// GcTimer tracks the timing of garbage-collection cycles, for use in `Stats`.  Overlapping cycles
+/// are assumed to end in the order they began.
+#[derive(Debug, Default)]
+pub(crate) struct GcTimer {
+    started: VecDeque<Instant>,
+    last_gc: Option<GcStats>,
+}
+
+impl GcTimer {
+    /// Record the beginning of a cycle
+    pub(crate) fn begin(&mut self) {
+        self.started.push_back(Instant::now());
+    }
+
+    /// Record the end of a cycle, with the objects it reclaimed
+    pub(crate) fn end(&mut self, objects_reclaimed: usize, bytes_reclaimed: u64) {
+        let duration = match self.started.pop_front() {
+            Some(started) => started.elapsed(),
+            None => Duration::from_secs(0),
+        };
+        self.last_gc = Some(GcStats {
+            objects_reclaimed,
+            bytes_reclaimed,
+            duration,
+        });
+    }
+
+    /// Record the end of a cycle that did not collect anything.  This does not change `last_gc`.
+    pub(crate) fn abort(&mut self) {
+        self.started.pop_front();
+    }
+
+    /// Get the stats for the most recently completed cycle
+    pub(crate) fn last_gc(&self) -> Option<GcStats> {
+        self.last_gc.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tally() {
+        let stats = Stats::tally(vec![(1, 10), (2, 5), (2, 7)].into_iter(), 2, 0);
+        assert_eq!(stats.objects, 3);
+        assert_eq!(stats.bytes, 22);
+        assert_eq!(stats.bytes_per_generation.get(&1), Some(&10));
+        assert_eq!(stats.bytes_per_generation.get(&2), Some(&12));
+        assert_eq!(stats.cur_generation, 2);
+        assert_eq!(stats.last_gc, None);
+    }
+
+    #[test]
+    fn timer() {
+        let mut timer = GcTimer::default();
+        assert_eq!(timer.last_gc(), None);
+        timer.begin();
+        timer.end(3, 30);
+        let last_gc = timer.last_gc().unwrap();
+        assert_eq!(last_gc.objects_reclaimed, 3);
+        assert_eq!(last_gc.bytes_reclaimed, 30);
+
+        // an aborted cycle does not count as the most recent cycle
+        timer.begin();
+        timer.abort();
+        assert_eq!(timer.last_gc().unwrap().objects_reclaimed, 3);
+        assert!(timer.started.is_empty());
+    }
+}
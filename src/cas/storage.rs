@@ -1,9 +1,16 @@
-use super::hash::Hash;
-use super::traits::{Content, CAS};
+use super::async_cas::AsyncCAS;
+use super::hash::{Algorithm, Hash};
+use super::lru::Lru;
+use super::pin::Pins;
+use super::prefix::resolve_prefix;
+use super::stats::{GcTimer, Stats};
+use super::traits::{Content, ObjectInfo, Objects, StoreAs, CAS};
+use async_trait::async_trait;
 use failure::{bail, err_msg, Fallible};
 use log::debug;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::mem;
 use std::sync::RwLock;
 
 // TODO: is the RwLock required?
@@ -11,13 +18,25 @@ use std::sync::RwLock;
 /// Type Storage provides a distributed content-addressible storage pool.  The content
 /// inserted into the mechanism can be of any type implementing the `rustc_serialize`
 /// traits `Decodable` and `Encodable`.
+///
+/// A storage pool created with `with_budget` acts as a cache: once its objects exceed the byte
+/// budget, the least-recently-used objects are evicted, without waiting for garbage collection.
+/// Storing, retrieving, or touching an object counts as a use.  Pinned objects, and objects
+/// stored or touched during a garbage-collection cycle that is still in progress, are never
+/// evicted, so the pool may exceed its budget if these alone exceed it.
 pub struct Storage(RwLock<Inner>);
 
 #[derive(Debug)]
 pub(crate) struct Inner {
-    map: HashMap<Hash, (u64, Content)>,
+    algorithm: Algorithm,
+    map: BTreeMap<Hash, (u64, Content)>,
     garbage_generation: u64,
     cur_generation: u64,
+    pins: Pins,
+    gc_timer: GcTimer,
+
+    /// Least-recently-used tracking, if this pool has a byte budget
+    lru: Option<Lru>,
 }
 
 impl fmt::Debug for Storage {
@@ -31,72 +50,284 @@ impl fmt::Debug for Storage {
 impl Storage {
     /// Create a new, empty storage pool.
     pub fn new() -> Storage {
+        Storage::with_algorithm(Algorithm::default())
+    }
+
+    /// Create a new, empty storage pool which hashes newly-stored content with the given
+    /// algorithm.
+    pub fn with_algorithm(algorithm: Algorithm) -> Storage {
+        Storage::make(algorithm, None)
+    }
+
+    /// Create a new, empty storage pool which evicts least-recently-used objects when the total
+    /// size of its objects exceeds the given number of bytes.  This is suitable for caching
+    /// objects that can be fetched again from elsewhere.
+    pub fn with_budget(budget: u64) -> Storage {
+        Storage::with_algorithm_and_budget(Algorithm::default(), budget)
+    }
+
+    /// Create a new, empty storage pool which hashes newly-stored content with the given
+    /// algorithm, and evicts least-recently-used objects as for `with_budget`.
+    pub fn with_algorithm_and_budget(algorithm: Algorithm, budget: u64) -> Storage {
+        Storage::make(algorithm, Some(budget))
+    }
+
+    fn make(algorithm: Algorithm, budget: Option<u64>) -> Storage {
         Storage(RwLock::new(Inner {
-            map: HashMap::new(),
+            algorithm,
+            map: BTreeMap::new(),
             garbage_generation: 0,
             cur_generation: 1,
+            pins: Pins::default(),
+            gc_timer: GcTimer::default(),
+            lru: budget.map(Lru::new),
         }))
     }
 }
 
-impl CAS for Storage {
-    fn store(&self, value: Content) -> Fallible<Hash> {
-        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
-
-        let cur_generation = inner.cur_generation;
-        let hash = Hash::for_bytes(&value);
-        debug!("store content with hash {:?}", hash);
-        inner.map.insert(hash.clone(), (cur_generation, value));
+impl Inner {
+    fn store(&mut self, value: Content) -> Hash {
+        let hash = self.algorithm.hash(&value);
+        self.store_as(&hash, value);
         // note that we assume no hash collisions of encoded values, since this is
         // not a security-sensitive context
-        Ok(hash)
+        hash
     }
 
-    fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
-        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+    fn store_as(&mut self, hash: &Hash, value: Content) {
+        debug!("store content with hash {:?}", hash);
+        let size = value.len() as u64;
+        let previous = self.map.insert(hash.clone(), (self.cur_generation, value));
+        if let Some(lru) = self.lru.as_mut() {
+            if let Some((_, previous)) = previous {
+                lru.removed(hash, previous.len() as u64);
+            }
+            lru.added(hash, size);
+            self.evict(hash);
+        }
+    }
+
+    /// Retrieve a value, recording the use if this pool has a byte budget
+    fn retrieve_and_use(&mut self, hash: &Hash) -> Fallible<Content> {
+        let value = self.retrieve(hash)?;
+        if let Some(lru) = self.lru.as_mut() {
+            lru.used(hash);
+        }
+        Ok(value)
+    }
+
+    /// Evict least-recently-used objects until this pool is within its byte budget, if it has
+    /// one.  The `keep` object, which was just stored, is never evicted.
+    fn evict(&mut self, keep: &Hash) {
+        let lru = match self.lru.as_mut() {
+            Some(lru) => lru,
+            None => return,
+        };
+        let mut excess = lru.excess();
+        if excess == 0 {
+            return;
+        }
+
+        let in_gc = self.cur_generation > self.garbage_generation + 1;
+        let mut victims = vec![];
+        for hash in lru.least_recent() {
+            if excess == 0 {
+                break;
+            }
+            if hash == keep || self.pins.is_pinned(hash) {
+                continue;
+            }
+            let (generation, value) = &self.map[hash];
+            if in_gc && *generation == self.cur_generation {
+                continue;
+            }
+            victims.push(hash.clone());
+            excess = excess.saturating_sub(value.len() as u64);
+        }
 
+        for hash in victims {
+            if let Some((_, value)) = self.map.remove(&hash) {
+                debug!("evict content with hash {:?}", hash);
+                lru.evicted(&hash, value.len() as u64);
+            }
+        }
+    }
+
+    fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
         debug!("retrieve content with hash {:?}", hash);
-        match inner.map.get(hash) {
+        match self.map.get(hash) {
             None => bail!("No object found"),
             Some(tup) => Ok(tup.1.clone()),
         }
     }
 
-    fn touch(&self, hash: &Hash) -> Fallible<()> {
-        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
-
+    fn touch(&mut self, hash: &Hash) -> Fallible<()> {
         debug!("touch content with hash {:?}", hash);
-        let cur_generation = inner.cur_generation;
-        match inner.map.remove(hash) {
+        match self.map.get_mut(hash) {
             None => bail!("No object found"),
             Some(tup) => {
-                inner.map.insert(hash.clone(), (cur_generation, tup.1));
+                tup.0 = self.cur_generation;
+                if let Some(lru) = self.lru.as_mut() {
+                    lru.used(hash);
+                }
                 Ok(())
             }
         }
     }
+}
+
+impl StoreAs for Storage {
+    fn store_as(&self, hash: &Hash, value: Content) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.store_as(hash, value);
+        Ok(())
+    }
+}
+
+impl CAS for Storage {
+    fn store(&self, value: Content) -> Fallible<Hash> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        Ok(inner.store(value))
+    }
+
+    fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        {
+            let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+            if inner.lru.is_none() {
+                return inner.retrieve(hash);
+            }
+        }
+        // recording the use requires a write lock
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.retrieve_and_use(hash)
+    }
+
+    fn contains(&self, hash: &Hash) -> Fallible<bool> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        Ok(inner.map.contains_key(hash))
+    }
+
+    fn store_many(&self, values: Vec<Content>) -> Fallible<Vec<Hash>> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        Ok(values.into_iter().map(|v| inner.store(v)).collect())
+    }
+
+    fn retrieve_many(&self, hashes: &[Hash]) -> Fallible<Vec<Content>> {
+        {
+            let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+            if inner.lru.is_none() {
+                return hashes.iter().map(|h| inner.retrieve(h)).collect();
+            }
+        }
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        hashes.iter().map(|h| inner.retrieve_and_use(h)).collect()
+    }
+
+    fn resolve_prefix(&self, prefix: &str) -> Fallible<Hash> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        resolve_prefix(&inner.map, prefix)
+    }
+
+    fn objects(&self) -> Fallible<Objects> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        let objects: Vec<ObjectInfo> = inner
+            .map
+            .iter()
+            .map(|(hash, (generation, value))| ObjectInfo {
+                hash: hash.clone(),
+                size: value.len() as u64,
+                generation: *generation,
+            })
+            .collect();
+        Ok(Box::new(objects.into_iter()))
+    }
+
+    fn touch(&self, hash: &Hash) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.touch(hash)
+    }
+
+    fn touch_many(&self, hashes: &[Hash]) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        for hash in hashes {
+            inner.touch(hash)?;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> Fallible<Stats> {
+        let inner = self.0.read().map_err(|_| err_msg("Lock Poisoned"))?;
+        let mut stats = Stats::tally(
+            inner.map.values().map(|(gen, v)| (*gen, v.len() as u64)),
+            inner.cur_generation,
+            inner.garbage_generation,
+        );
+        stats.last_gc = inner.gc_timer.last_gc();
+        if let Some(lru) = inner.lru.as_ref() {
+            let (objects_evicted, bytes_evicted) = lru.evictions();
+            stats.objects_evicted = objects_evicted;
+            stats.bytes_evicted = bytes_evicted;
+        }
+        Ok(stats)
+    }
+
+    fn pin(&self, hash: &Hash) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        if !inner.map.contains_key(hash) {
+            bail!("No object found");
+        }
+        inner.pins.pin(hash);
+        Ok(())
+    }
+
+    fn unpin(&self, hash: &Hash) -> Fallible<()> {
+        let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.pins.unpin(hash)
+    }
 
     fn begin_gc(&self) -> Fallible<()> {
         let mut inner = self.0.write().map_err(|_| err_msg("Lock Poisoned"))?;
+        inner.gc_timer.begin();
         inner.cur_generation += 1;
         debug!("begin_gc: cur_generation={}", inner.cur_generation);
         Ok(())
     }
 
+    fn abort_gc(&self) {
+        // see end_gc regarding lock failures
+        if let Ok(mut inner) = self.0.write() {
+            // objects that were not touched are now in the garbage generation, so they are
+            // collected when the next cycle ends
+            inner.garbage_generation += 1;
+            debug!("abort_gc: garbage_generation={}", inner.garbage_generation);
+            inner.gc_timer.abort();
+        }
+    }
+
     fn end_gc(&self) {
         if let Ok(mut inner) = self.0.write() {
             inner.garbage_generation += 1;
             debug!("endn_gc: garbage_generation={}", inner.garbage_generation);
             let garbage_generation = inner.garbage_generation;
 
+            // pinned objects are never collected, so they are always present to be touched
+            for hash in inner.pins.hashes() {
+                let _ = inner.touch(&hash);
+            }
+
             // generate a new map containing only non-garbage
-            let mut new_map = HashMap::new();
-            for (k, v) in inner.map.drain() {
-                if v.0 > garbage_generation {
-                    new_map.insert(k, v);
+            let old_map = mem::take(&mut inner.map);
+            let (map, garbage): (BTreeMap<_, _>, BTreeMap<_, _>) = old_map
+                .into_iter()
+                .partition(|(_, v)| v.0 > garbage_generation);
+            inner.map = map;
+            if let Some(lru) = inner.lru.as_mut() {
+                for (hash, (_, value)) in garbage.iter() {
+                    lru.removed(hash, value.len() as u64);
                 }
             }
-            inner.map = new_map;
+            let bytes = garbage.values().map(|v| v.1.len() as u64).sum();
+            inner.gc_timer.end(garbage.len(), bytes);
         } else {
             // locking fails only with a PoisonError, which is basically fatal,
             // but we cannot return an error right now.  So, leave the GC cycle
@@ -105,11 +336,40 @@ impl CAS for Storage {
     }
 }
 
+#[async_trait]
+impl AsyncCAS for Storage {
+    // these operations do no I/O and hold a std lock only while working on the in-memory pool
+    // (for end_gc, while sweeping it), so they run directly in the async context
+    async fn store(&self, value: Content) -> Fallible<Hash> {
+        CAS::store(self, value)
+    }
+
+    async fn retrieve(&self, hash: &Hash) -> Fallible<Content> {
+        CAS::retrieve(self, hash)
+    }
+
+    async fn touch(&self, hash: &Hash) -> Fallible<()> {
+        CAS::touch(self, hash)
+    }
+
+    async fn begin_gc(&self) -> Fallible<()> {
+        CAS::begin_gc(self)
+    }
+
+    async fn end_gc(&self) {
+        CAS::end_gc(self)
+    }
+
+    async fn abort_gc(&self) {
+        CAS::abort_gc(self)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Storage;
-    use crate::cas::hash::Hash;
-    use crate::cas::traits::CAS;
+    use crate::cas::hash::{Algorithm, Hash};
+    use crate::cas::traits::{ObjectInfo, CAS};
     use crate::util::test::init_env_logger;
 
     #[test]
@@ -127,6 +387,125 @@ mod tests {
         assert!(storage.retrieve(&badhash).is_err());
     }
 
+    #[test]
+    fn with_algorithm() {
+        let storage = Storage::with_algorithm(Algorithm::Blake3);
+
+        let hash = storage.store(b"fast".to_vec()).unwrap();
+        assert_eq!(hash.algorithm(), Some(Algorithm::Blake3));
+        assert!(hash.verify(b"fast"));
+        assert_eq!(storage.retrieve(&hash).unwrap(), b"fast".to_vec());
+    }
+
+    #[test]
+    fn resolve_prefix() {
+        let storage = Storage::new();
+
+        let hash = storage.store(b"one".to_vec()).unwrap();
+        let prefix = &hash.to_hex()[..7];
+        assert_eq!(storage.resolve_prefix(prefix).unwrap(), hash);
+        assert!(storage.resolve_prefix("0000000").is_err());
+
+        // after GC, the object no longer resolves
+        storage.begin_gc().unwrap();
+        storage.end_gc();
+        assert!(storage.resolve_prefix(prefix).is_err());
+    }
+
+    #[test]
+    fn contains() {
+        let storage = Storage::new();
+
+        let hash = storage.store(b"here".to_vec()).unwrap();
+        assert!(storage.contains(&hash).unwrap());
+        assert!(!storage.contains(&Hash::from_hex("1234")).unwrap());
+    }
+
+    #[test]
+    fn batch() {
+        let storage = Storage::new();
+
+        let hashes = storage
+            .store_many(vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()])
+            .unwrap();
+        assert_eq!(hashes[1], Hash::for_bytes(&b"b".to_vec()));
+        assert_eq!(
+            storage.retrieve_many(&hashes[1..]).unwrap(),
+            vec![b"b".to_vec(), b"c".to_vec()]
+        );
+        assert!(storage
+            .retrieve_many(&[hashes[0].clone(), Hash::from_hex("1234")])
+            .is_err());
+
+        storage.begin_gc().unwrap();
+        storage.touch_many(&hashes[..2]).unwrap();
+        assert!(storage.touch_many(&[Hash::from_hex("1234")]).is_err());
+        storage.end_gc();
+
+        assert!(storage.contains(&hashes[0]).unwrap());
+        assert!(storage.contains(&hashes[1]).unwrap());
+        assert!(!storage.contains(&hashes[2]).unwrap());
+    }
+
+    #[test]
+    fn stats() {
+        let storage = Storage::new();
+        storage.store(b"abc".to_vec()).unwrap();
+        let hash = storage.store(b"defgh".to_vec()).unwrap();
+
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.objects, 2);
+        assert_eq!(stats.bytes, 8);
+        assert_eq!(stats.bytes_per_generation.get(&1), Some(&8));
+        assert_eq!(stats.last_gc, None);
+
+        storage.begin_gc().unwrap();
+        storage.touch(&hash).unwrap();
+        storage.end_gc();
+
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.objects, 1);
+        assert_eq!(stats.bytes, 5);
+        assert_eq!(stats.bytes_per_generation.get(&2), Some(&5));
+        assert_eq!(stats.cur_generation, 2);
+        assert_eq!(stats.garbage_generation, 1);
+        let last_gc = stats.last_gc.unwrap();
+        assert_eq!(last_gc.objects_reclaimed, 1);
+        assert_eq!(last_gc.bytes_reclaimed, 3);
+    }
+
+    #[test]
+    fn objects() {
+        let storage = Storage::new();
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        storage.begin_gc().unwrap();
+        let hash2 = storage.store(b"defgh".to_vec()).unwrap();
+
+        let mut objects = storage.objects().unwrap();
+
+        // the iterator is a snapshot, so this store does not appear, and does not deadlock
+        storage.store(b"later".to_vec()).unwrap();
+
+        let mut found: Vec<ObjectInfo> = objects.by_ref().collect();
+        found.sort_by_key(|o| o.generation);
+        assert_eq!(
+            found,
+            vec![
+                ObjectInfo {
+                    hash: hash1,
+                    size: 3,
+                    generation: 1
+                },
+                ObjectInfo {
+                    hash: hash2,
+                    size: 5,
+                    generation: 2
+                },
+            ]
+        );
+        storage.end_gc();
+    }
+
     #[test]
     fn put_twice() {
         let storage = super::Storage::new();
@@ -151,6 +530,34 @@ mod tests {
         assert!(storage.touch(&Hash::from_hex("1234")).is_err());
     }
 
+    #[test]
+    fn pin() {
+        let storage = super::Storage::new();
+
+        let hash1 = storage.store(b"pinned".to_vec()).unwrap();
+        let hash2 = storage.store(b"unpinned".to_vec()).unwrap();
+        storage.pin(&hash1).unwrap();
+        storage.pin(&hash1).unwrap();
+        assert!(storage.pin(&Hash::from_hex("1234")).is_err());
+        assert!(storage.unpin(&hash2).is_err());
+
+        storage.begin_gc().unwrap();
+        storage.end_gc();
+        assert!(storage.contains(&hash1).unwrap());
+        assert!(!storage.contains(&hash2).unwrap());
+
+        // still pinned once
+        storage.unpin(&hash1).unwrap();
+        storage.begin_gc().unwrap();
+        storage.end_gc();
+        assert!(storage.contains(&hash1).unwrap());
+
+        storage.unpin(&hash1).unwrap();
+        storage.begin_gc().unwrap();
+        storage.end_gc();
+        assert!(!storage.contains(&hash1).unwrap());
+    }
+
     #[test]
     fn gc() {
         let storage = super::Storage::new();
@@ -172,4 +579,133 @@ mod tests {
         assert!(storage.retrieve(&hash3).is_ok()); // stored
         assert!(storage.retrieve(&hash4).is_err()); // not referenced
     }
+
+    #[test]
+    fn aborted_gc() {
+        let storage = super::Storage::new();
+
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        let hash2 = storage.store(b"def".to_vec()).unwrap();
+
+        storage.begin_gc().unwrap();
+        storage.touch(&hash1).unwrap();
+        storage.abort_gc();
+        assert!(storage.contains(&hash2).unwrap());
+        assert_eq!(storage.stats().unwrap().last_gc, None);
+
+        storage.begin_gc().unwrap();
+        storage.touch(&hash1).unwrap();
+        storage.end_gc();
+        assert!(storage.contains(&hash1).unwrap());
+        assert!(!storage.contains(&hash2).unwrap());
+
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.garbage_generation + 1, stats.cur_generation);
+        assert_eq!(stats.last_gc.unwrap().objects_reclaimed, 1);
+    }
+
+    #[test]
+    fn budget_evicts_lru() {
+        let storage = super::Storage::with_budget(9);
+
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        let hash2 = storage.store(b"def".to_vec()).unwrap();
+        let hash3 = storage.store(b"ghi".to_vec()).unwrap();
+        storage.retrieve(&hash1).unwrap();
+        let hash4 = storage.store(b"jkl".to_vec()).unwrap();
+
+        // hash2 was least recently used
+        assert!(storage.contains(&hash1).unwrap());
+        assert!(!storage.contains(&hash2).unwrap());
+        assert!(storage.contains(&hash3).unwrap());
+        assert!(storage.contains(&hash4).unwrap());
+
+        storage.touch(&hash3).unwrap();
+        storage.store(b"mnopqr".to_vec()).unwrap();
+        assert!(!storage.contains(&hash1).unwrap());
+        assert!(storage.contains(&hash3).unwrap());
+        assert!(!storage.contains(&hash4).unwrap());
+
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.objects, 2);
+        assert_eq!(stats.bytes, 9);
+        assert_eq!(stats.objects_evicted, 3);
+        assert_eq!(stats.bytes_evicted, 9);
+    }
+
+    #[test]
+    fn budget_keeps_new_object() {
+        let storage = super::Storage::with_budget(2);
+        let hash = storage.store(b"abc".to_vec()).unwrap();
+        assert!(storage.contains(&hash).unwrap());
+        assert_eq!(storage.stats().unwrap().objects_evicted, 0);
+    }
+
+    #[test]
+    fn budget_exempts_pinned() {
+        let storage = super::Storage::with_budget(6);
+
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        storage.pin(&hash1).unwrap();
+        let hash2 = storage.store(b"def".to_vec()).unwrap();
+        let hash3 = storage.store(b"ghi".to_vec()).unwrap();
+
+        assert!(storage.contains(&hash1).unwrap());
+        assert!(!storage.contains(&hash2).unwrap());
+        assert!(storage.contains(&hash3).unwrap());
+    }
+
+    #[test]
+    fn budget_exempts_current_generation_during_gc() {
+        let storage = super::Storage::with_budget(6);
+
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        let hash2 = storage.store(b"def".to_vec()).unwrap();
+
+        storage.begin_gc().unwrap();
+        storage.touch(&hash1).unwrap();
+        storage.touch(&hash2).unwrap();
+        let hash3 = storage.store(b"ghi".to_vec()).unwrap();
+
+        // everything is in the current generation, so the pool exceeds its budget
+        assert!(storage.contains(&hash1).unwrap());
+        assert!(storage.contains(&hash2).unwrap());
+        assert!(storage.contains(&hash3).unwrap());
+        storage.end_gc();
+
+        // outside of a GC cycle, eviction resumes
+        let hash4 = storage.store(b"jkl".to_vec()).unwrap();
+        assert!(!storage.contains(&hash1).unwrap());
+        assert!(!storage.contains(&hash2).unwrap());
+        assert!(storage.contains(&hash3).unwrap());
+        assert!(storage.contains(&hash4).unwrap());
+    }
+
+    #[test]
+    fn budget_with_algorithm() {
+        let storage = super::Storage::with_algorithm_and_budget(Algorithm::Blake3, 6);
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        let hash2 = storage.store(b"defg".to_vec()).unwrap();
+        assert_eq!(hash2.algorithm(), Some(Algorithm::Blake3));
+        assert!(!storage.contains(&hash1).unwrap());
+        assert!(storage.contains(&hash2).unwrap());
+    }
+
+    #[test]
+    fn budget_gc() {
+        let storage = super::Storage::with_budget(6);
+
+        let hash1 = storage.store(b"abc".to_vec()).unwrap();
+        storage.store(b"def".to_vec()).unwrap();
+
+        storage.begin_gc().unwrap();
+        storage.touch(&hash1).unwrap();
+        storage.end_gc();
+
+        // garbage no longer counts against the budget
+        let hash3 = storage.store(b"ghi".to_vec()).unwrap();
+        assert!(storage.contains(&hash1).unwrap());
+        assert!(storage.contains(&hash3).unwrap());
+        assert_eq!(storage.stats().unwrap().objects_evicted, 0);
+    }
 }
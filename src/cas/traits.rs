@@ -1,8 +1,26 @@
 use super::hash::Hash;
-use failure::Fallible;
+use super::stats::Stats;
+use failure::{bail, Fallible};
+use std::any::type_name;
 
 pub type Content = Vec<u8>;
 
+/// Information about an object in a storage pool, as returned by `CAS::objects`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectInfo {
+    /// The object's hash
+    pub hash: Hash,
+
+    /// The size of the object's content, in bytes
+    pub size: u64,
+
+    /// The garbage-collection generation in which the object was last stored or touched
+    pub generation: u64,
+}
+
+/// An iterator over the objects in a storage pool
+pub type Objects = Box<dyn Iterator<Item = ObjectInfo> + Send>;
+
 /// Content Addressible Storage
 ///
 /// When values are stored in this structure, their contents are hashed and the hash
@@ -26,7 +44,11 @@ pub type Content = Vec<u8>;
 ///  * It ensures that non-garbage objects are available locally (fetching from another node
 ///    if necessary)
 ///  * It provides a means to checkpoint storage to disk: each generation is written to a new
-///    file, and once the scan is complete any previous files can be discarded.
+///    file, and once the scan is complete any previous files can be discarded.  This is
+///    implemented by `CheckpointStorage`.
+///
+/// Objects can also be pinned with `pin`, in which case they survive garbage collection without
+/// being touched, until they are unpinned.
 ///
 /// Garbage collection runs can overlap, although this is not recommended.
 pub trait CAS: std::fmt::Debug {
@@ -39,10 +61,89 @@ pub trait CAS: std::fmt::Debug {
     /// Retrieve a value by hash.
     fn retrieve(&self, hash: &Hash) -> Fallible<Content>;
 
+    /// Determine whether a value is present, without retrieving it.
+    fn contains(&self, hash: &Hash) -> Fallible<bool> {
+        Ok(self.retrieve(hash).is_ok())
+    }
+
+    /// Store several values, returning their hashes in the same order.
+    fn store_many(&self, values: Vec<Content>) -> Fallible<Vec<Hash>> {
+        values.into_iter().map(|v| self.store(v)).collect()
+    }
+
+    /// Retrieve several values by hash, returning them in the same order.  This fails if any of
+    /// the values is not found.
+    fn retrieve_many(&self, hashes: &[Hash]) -> Fallible<Vec<Content>> {
+        hashes.iter().map(|h| self.retrieve(h)).collect()
+    }
+
+    /// Resolve an abbreviated hex prefix (at least `MIN_PREFIX_LEN` characters) to the hash of
+    /// the single stored object that begins with that prefix.  If no objects match, this fails
+    /// with `Error::PrefixNotFound`; if several match, it fails with `Error::AmbiguousPrefix`
+    /// listing the candidates.
+    ///
+    /// This is intended for human use, such as debugging, and the default implementation
+    /// simply fails.
+    fn resolve_prefix(&self, prefix: &str) -> Fallible<Hash> {
+        bail!(
+            "{} does not support prefix lookup of {}",
+            type_name::<Self>(),
+            prefix
+        )
+    }
+
+    /// Iterate over all objects in the storage pool, in no particular order.  The iterator
+    /// reflects a snapshot of the storage pool taken when this method is called, so it is not
+    /// affected by concurrent operations.  Implementations copy the object metadata, which is
+    /// small, rather than holding a lock while the caller iterates, since the caller may store
+    /// or touch objects as it goes.  This is intended for maintenance operations such as
+    /// `scrub` and backups, and the default implementation simply fails.
+    fn objects(&self) -> Fallible<Objects> {
+        bail!("{} does not support listing objects", type_name::<Self>())
+    }
+
+    /// Get statistics about the storage pool, such as the number and size of objects and the
+    /// results of the most recent garbage collection.  The default implementation simply fails.
+    fn stats(&self) -> Fallible<Stats> {
+        bail!("{} does not support statistics", type_name::<Self>())
+    }
+
     /// Mark a value as part of the current garbage-collection generation.  This will fetch
     /// the value from another node if necessary and thus may fail.
     fn touch(&self, hash: &Hash) -> Fallible<()>;
 
+    /// Mark several values as part of the current garbage-collection generation.  This fails if
+    /// any of the values is not found.
+    fn touch_many(&self, hashes: &[Hash]) -> Fallible<()> {
+        for hash in hashes {
+            self.touch(hash)?;
+        }
+        Ok(())
+    }
+
+    /// Pin a value, so that it survives garbage collection without being touched.  Pins are
+    /// reference-counted: a value pinned several times remains pinned until it has been unpinned
+    /// the same number of times.  This fails if the value is not found.  Use `PinGuard` to unpin
+    /// automatically.
+    ///
+    /// The default implementation simply fails, since ignoring a pin could lose data.
+    fn pin(&self, hash: &Hash) -> Fallible<()> {
+        bail!(
+            "{} does not support pinning {:?}",
+            type_name::<Self>(),
+            hash
+        )
+    }
+
+    /// Unpin a value previously pinned with `pin`.  This fails if the value is not pinned.
+    fn unpin(&self, hash: &Hash) -> Fallible<()> {
+        bail!(
+            "{} does not support pinning {:?}",
+            type_name::<Self>(),
+            hash
+        )
+    }
+
     /// Begin a garbage collection round.  Before dropping the resulting `GarbageCollection`
     /// instance, `touch` or `store` all non-garbage objects.
     fn begin_gc(&self) -> Fallible<()>;
@@ -50,4 +151,28 @@ pub trait CAS: std::fmt::Debug {
     /// Complete a garbage collection round.  This should be called exactly once per call
     /// to `begin_gc`.  Use `GarbageCycle` to ensure this.
     fn end_gc(&self);
+
+    /// Complete a garbage collection round without collecting anything, such as when not every
+    /// non-garbage object could be touched.  This is called instead of `end_gc`.  Objects not
+    /// touched in the round remain, and are collected by the next round that ends normally.
+    fn abort_gc(&self);
+}
+
+/// Storage that can hold bytes under a hash that is not their own hash.  `CompressedStorage` and
+/// `EncryptedStorage` require this of the storage they wrap, so that they can store an encoded
+/// form of a value under the hash of the original value.
+///
+/// Storing arbitrary bytes under an arbitrary hash would break the content-addressing guarantee,
+/// so this trait is sealed: it can be used as a bound, but it cannot be implemented outside this
+/// crate, and its method can only be called within it.  It is implemented by `Storage`,
+/// `DiskStorage`, `CheckpointStorage`, `ShardedStorage`, and the two wrappers themselves.
+pub trait RawCAS: StoreAs {}
+
+impl<T: StoreAs> RawCAS for T {}
+
+/// The method of `RawCAS`.  This is public, so that it can be a supertrait of `RawCAS`, but it
+/// is not exported, so it cannot be named outside this crate.
+pub trait StoreAs: CAS {
+    /// Store a value under the given hash, without checking that it is the hash of the value.
+    fn store_as(&self, hash: &Hash, value: Content) -> Fallible<()>;
 }
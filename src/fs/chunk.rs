@@ -0,0 +1,134 @@
+use super::content::Content;
+use super::fs::FileSystem;
+use super::lazy::LazyContent;
+use crate::cas::Hash;
+use failure::{bail, Fallible};
+
+/// Values larger than this are split into chunks, rather than being stored inline in the tree.
+pub(crate) const CHUNK_THRESHOLD: usize = 64 * 1024;
+
+/// No chunk boundary is placed within this many bytes of the previous boundary.
+const MIN_CHUNK: usize = 4 * 1024;
+
+/// A chunk boundary is always placed this many bytes after the previous boundary.
+const MAX_CHUNK: usize = 64 * 1024;
+
+/// A boundary occurs where the top BOUNDARY_BITS bits of the rolling hash are zero, so chunks
+/// average about MIN_CHUNK + 2^BOUNDARY_BITS bytes.
+const BOUNDARY_BITS: u32 = 13;
+
+/// Generate the table of random values used by the rolling hash.  This must be the same on every
+/// node, so it is generated from a fixed seed (using splitmix64).
+fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x7275_6262_6973_6821; // "rubbish!"
+    for entry in table.iter_mut() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        *entry = z ^ (z >> 31);
+    }
+    table
+}
+
+/// Split data into content-defined chunks.  Boundaries are determined by a "gear" rolling hash
+/// over the preceding bytes, so an insertion or deletion in a large value only changes the chunks
+/// near the modification, and the remaining chunks are shared with the original value.
+pub(crate) fn split(data: &[u8]) -> Vec<&[u8]> {
+    let gear = gear_table();
+    let mut chunks = vec![];
+    let mut start = 0;
+    let mut hash: u64 = 0;
+
+    for (i, b) in data.iter().enumerate() {
+        hash = (hash << 1).wrapping_add(gear[*b as usize]);
+        let len = i + 1 - start;
+        if (len >= MIN_CHUNK && hash >> (64 - BOUNDARY_BITS) == 0) || len >= MAX_CHUNK {
+            chunks.push(&data[start..=i]);
+            start = i + 1;
+            hash = 0;
+        }
+    }
+    if start < data.len() {
+        chunks.push(&data[start..]);
+    }
+    chunks
+}
+
+/// Store data as a sequence of chunks, returning the hash of the manifest listing those chunks.
+pub(crate) fn store_chunked(fs: &FileSystem, data: &[u8]) -> Fallible<Hash> {
+    let chunks = split(data).iter().map(|c| c.to_vec()).collect();
+    let chunks = fs.storage.store_many(chunks)?;
+    Content::Manifest { chunks }.store_in(fs)
+}
+
+/// Load data stored with `store_chunked`.
+pub(crate) fn load_chunked(fs: &FileSystem, manifest: &Hash) -> Fallible<Vec<u8>> {
+    let chunks = match Content::retrieve_from(fs, manifest)? {
+        Content::Manifest { chunks } => chunks,
+        _ => bail!("{:?} is not a manifest", manifest),
+    };
+    Ok(fs.storage.retrieve_many(&chunks)?.concat())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::cas::LocalStorage;
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+
+    /// Generate some random data, repeatably
+    pub(crate) fn random_data(seed: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        StdRng::seed_from_u64(seed).fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn split_small() {
+        assert_eq!(split(&[]).len(), 0);
+        assert_eq!(split(&[1, 2, 3]), vec![&[1, 2, 3]]);
+    }
+
+    #[test]
+    fn split_sizes() {
+        let data = random_data(1, 1024 * 1024);
+        let chunks = split(&data);
+        assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), data.len());
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= MIN_CHUNK);
+            assert!(chunk.len() <= MAX_CHUNK);
+        }
+        // roughly the expected number of chunks
+        assert!(chunks.len() > 30);
+        assert!(chunks.len() < 200);
+    }
+
+    #[test]
+    fn split_insertion() {
+        let data = random_data(2, 1024 * 1024);
+        let mut modified = data.clone();
+        modified.splice(500_000..500_000, vec![1, 2, 3, 4, 5]);
+
+        let chunks = split(&data);
+        let modified_chunks = split(&modified);
+        let shared = modified_chunks
+            .iter()
+            .filter(|c| chunks.contains(c))
+            .count();
+        // only a chunk or two near the insertion should differ
+        assert!(shared >= modified_chunks.len() - 2);
+    }
+
+    #[test]
+    fn store_and_load() {
+        let storage = LocalStorage::new();
+        let fs = FileSystem::new(Box::new(storage));
+
+        let data = random_data(3, 300 * 1024);
+        let manifest = store_chunked(&fs, &data).unwrap();
+        assert_eq!(load_chunked(&fs, &manifest).unwrap(), data);
+    }
+}
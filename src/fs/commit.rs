@@ -1,10 +1,17 @@
 use super::content::Content;
+use super::diff::Change;
 use super::fs::FileSystem;
 use super::lazy::LazyHashedObject;
 use super::tree::Tree;
 use crate::cas::Hash;
-use failure::Fallible;
+use bincode::{
+    rustc_serialize::{decode, encode},
+    SizeLimit,
+};
+use failure::{bail, Fallible};
+use std::collections::BTreeMap;
 use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 // TODO: use pub(crate)
 
@@ -15,6 +22,124 @@ pub struct Commit {
     inner: Rc<LazyHashedObject<Content>>,
 }
 
+/// Metadata describes who made a commit, when, and why.  Commits made without metadata, including
+/// the root commit, have the default (empty) metadata.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    /// The author of the commit, such as a user name or node id
+    pub author: String,
+
+    /// The time the commit was made, in seconds since the UNIX epoch, or 0 if not known
+    pub timestamp: u64,
+
+    /// A free-form description of the commit
+    pub message: String,
+
+    /// Additional application-defined key-value pairs
+    pub annotations: BTreeMap<String, String>,
+}
+
+/// The version of the metadata encoding used for new commits
+const METADATA_VERSION: u32 = 1;
+
+/// Version 1 of the metadata encoding: author, timestamp, message, and annotations, in that order
+type MetadataV1 = (String, u64, String, BTreeMap<String, String>);
+
+impl Metadata {
+    /// Encode this metadata with the current version, returning the version and the encoded
+    /// bytes
+    fn encode(self) -> Fallible<(u32, Vec<u8>)> {
+        let v1: MetadataV1 = (self.author, self.timestamp, self.message, self.annotations);
+        // BTreeMap encodes in sorted order, so this is stable
+        Ok((METADATA_VERSION, encode(&v1, SizeLimit::Infinite)?))
+    }
+
+    /// Decode metadata encoded with the given version.  Support for older versions must be
+    /// retained, so that existing commits remain readable.
+    fn decode(version: u32, bytes: &[u8]) -> Fallible<Metadata> {
+        match version {
+            1 => {
+                let (author, timestamp, message, annotations): MetadataV1 = decode(bytes)?;
+                Ok(Metadata {
+                    author,
+                    timestamp,
+                    message,
+                    annotations,
+                })
+            }
+            _ => bail!("unsupported commit metadata version {}", version),
+        }
+    }
+}
+
+/// A CommitBuilder is used to set the metadata for a new commit.  Create one with
+/// `Commit::builder`.
+#[derive(Debug)]
+pub struct CommitBuilder {
+    parents: Vec<Commit>,
+    tree: Tree,
+    metadata: Metadata,
+}
+
+impl CommitBuilder {
+    /// Set the author of the commit
+    pub fn author<S: Into<String>>(mut self, author: S) -> Self {
+        self.metadata.author = author.into();
+        self
+    }
+
+    /// Set the timestamp of the commit, in seconds since the UNIX epoch
+    pub fn timestamp(mut self, timestamp: u64) -> Self {
+        self.metadata.timestamp = timestamp;
+        self
+    }
+
+    /// Set the timestamp of the commit to the current time
+    pub fn now(self) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.timestamp(now)
+    }
+
+    /// Set the commit message
+    pub fn message<S: Into<String>>(mut self, message: S) -> Self {
+        self.metadata.message = message.into();
+        self
+    }
+
+    /// Add an annotation, replacing any existing annotation with the same key
+    pub fn annotation<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.metadata.annotations.insert(key.into(), value.into());
+        self
+    }
+
+    /// Build the commit.  As with trees, the commit is not stored until its hash is requested.
+    pub fn build(self, fs: &FileSystem) -> Fallible<Commit> {
+        let parents = self
+            .parents
+            .iter()
+            .map(|p| Ok(p.hash(fs)?.clone()))
+            .collect::<Fallible<Vec<Hash>>>()?;
+        let tree = self.tree.hash(fs)?.clone();
+        let content = if self.metadata == Metadata::default() {
+            Content::Commit { parents, tree }
+        } else {
+            let (version, metadata) = self.metadata.encode()?;
+            Content::AnnotatedCommit {
+                parents,
+                tree,
+                version,
+                metadata,
+            }
+        };
+        Ok(Commit {
+            inner: Rc::new(LazyHashedObject::for_content(content)),
+        })
+    }
+}
+
 impl Commit {
     /// Return a root commit
     pub fn root(fs: &FileSystem) -> Fallible<Commit> {
@@ -34,15 +159,30 @@ impl Commit {
         }
     }
 
-    /// Make a new commit that is a child of this one, with the given tree
+    /// Begin building a new commit with the given parents and tree.  Metadata is set with the
+    /// returned builder.
+    pub fn builder(parents: &[Commit], tree: &Tree) -> CommitBuilder {
+        CommitBuilder {
+            parents: parents.to_vec(),
+            tree: tree.clone(),
+            metadata: Metadata::default(),
+        }
+    }
+
+    /// Make a new commit that is a child of this one, with the given tree and no metadata.  This
+    /// is equivalent to `Commit::builder(&[self.clone()], tree).build(fs)`.
     pub fn make_child(&self, fs: &FileSystem, tree: &Tree) -> Fallible<Commit> {
-        let content = Content::Commit {
-            parents: vec![self.hash(fs)?.clone()],
-            tree: tree.hash(fs)?.clone(),
-        };
-        Ok(Commit {
-            inner: Rc::new(LazyHashedObject::for_content(content)),
-        })
+        Commit::builder(std::slice::from_ref(self), tree).build(fs)
+    }
+
+    /// Make a new merge commit with the given parents, usually the tree from `Tree::merge` of the
+    /// parents' trees.  All parents are recorded, in the order given.  To add metadata to a merge
+    /// commit, use `Commit::builder`.
+    pub fn make_merge(fs: &FileSystem, parents: &[Commit], tree: &Tree) -> Fallible<Commit> {
+        if parents.len() < 2 {
+            bail!("a merge commit must have at least two parents");
+        }
+        Commit::builder(parents, tree).build(fs)
     }
 
     /// Get the hash for this commit
@@ -50,33 +190,79 @@ impl Commit {
         self.inner.hash(fs)
     }
 
+    /// Utility function to get the parent hashes and tree hash, failing if this is not a commit
+    fn content(&self, fs: &FileSystem) -> Fallible<(&[Hash], &Hash)> {
+        match self.inner.content(fs)? {
+            Content::Commit { parents, tree } | Content::AnnotatedCommit { parents, tree, .. } => {
+                Ok((parents, tree))
+            }
+            _ => bail!("{:?} is not a commit", self.inner.hash(fs)?),
+        }
+    }
+
+    /// Get the hashes of the parents of this commit, without creating a Commit for each
+    pub(crate) fn parent_hashes(&self, fs: &FileSystem) -> Fallible<&[Hash]> {
+        let (parents, _) = self.content(fs)?;
+        Ok(parents)
+    }
+
     /// Get the parents of this commit
     pub fn parents(&self, fs: &FileSystem) -> Fallible<Vec<Commit>> {
-        let content = self.inner.content(fs)?;
-        if let Content::Commit { parents, tree: _ } = content {
-            Ok(parents[..].iter().map(|h| Commit::for_hash(&h)).collect())
-        } else {
-            panic!("{:?} is not a commit", self.inner.hash(fs).unwrap());
-        }
+        let (parents, _) = self.content(fs)?;
+        Ok(parents.iter().map(Commit::for_hash).collect())
     }
 
     /// Get the Tree associated with this commit
     pub fn tree(&self, fs: &FileSystem) -> Fallible<Tree> {
-        let content = self.inner.content(fs)?;
-        if let Content::Commit { parents: _, tree } = content {
-            Ok(Tree::for_hash(&tree))
-        } else {
-            panic!("{:?} is not a commit", self.inner.hash(fs).unwrap());
+        let (_, tree) = self.content(fs)?;
+        Ok(Tree::for_hash(tree))
+    }
+
+    /// Get the metadata for this commit
+    pub fn metadata(&self, fs: &FileSystem) -> Fallible<Metadata> {
+        match self.inner.content(fs)? {
+            Content::Commit { .. } => Ok(Metadata::default()),
+            Content::AnnotatedCommit {
+                version, metadata, ..
+            } => Metadata::decode(*version, metadata),
+            _ => bail!("{:?} is not a commit", self.inner.hash(fs)?),
+        }
+    }
+
+    /// Get the timestamp for this commit, for ordering history.  This is 0 if the commit's
+    /// metadata cannot be decoded, such as when it was written with a later metadata version.
+    pub(crate) fn timestamp(&self, fs: &FileSystem) -> Fallible<u64> {
+        match self.inner.content(fs)? {
+            Content::Commit { .. } => Ok(0),
+            Content::AnnotatedCommit {
+                version, metadata, ..
+            } => Ok(Metadata::decode(*version, metadata)
+                .map(|m| m.timestamp)
+                .unwrap_or(0)),
+            _ => bail!("{:?} is not a commit", self.inner.hash(fs)?),
         }
     }
+
+    /// Get the changes this commit made to the tree of its first parent.  For a root commit, the
+    /// changes are relative to an empty tree.
+    pub fn diff_parent(&self, fs: &FileSystem) -> Fallible<Vec<Change>> {
+        let parent_tree = match self.parents(fs)?.first() {
+            Some(parent) => parent.tree(fs)?,
+            None => Tree::empty(),
+        };
+        parent_tree.diff(fs, &self.tree(fs)?)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Commit;
+    use super::{Commit, Metadata};
     use crate::cas::Hash;
     use crate::cas::LocalStorage;
+    use crate::fs::content::Content;
+    use crate::fs::diff::Change;
     use crate::fs::hashes::{EMPTY_TREE_HASH, ROOT_HASH};
+    use crate::fs::lazy::LazyContent;
     use crate::fs::tree::Tree;
     use crate::fs::FileSystem;
 
@@ -135,4 +321,168 @@ mod test {
         assert_eq!(parents[0].hash(&fs).unwrap(), &Hash::from_hex(ROOT_HASH));
         assert_eq!(child.tree(&fs).unwrap().hash(&fs).unwrap(), &tree_hash);
     }
+
+    #[test]
+    fn test_diff_parent() {
+        let storage = LocalStorage::new();
+        let fs = FileSystem::new(Box::new(storage));
+
+        let root = Commit::root(&fs).unwrap();
+        assert_eq!(root.diff_parent(&fs).unwrap(), vec![]);
+
+        let tree = Tree::empty().write(&fs, &["a"], vec![1]).unwrap();
+        let child = root.make_child(&fs, &tree).unwrap();
+        let tree = tree.write(&fs, &["a"], vec![2]).unwrap();
+        let grandchild = child.make_child(&fs, &tree).unwrap();
+
+        assert_eq!(
+            grandchild.diff_parent(&fs).unwrap(),
+            vec![Change::Modified {
+                path: vec!["a".to_string()],
+                old: vec![1],
+                new: vec![2],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_make_merge() {
+        let storage = LocalStorage::new();
+        let fs = FileSystem::new(Box::new(storage));
+
+        let root = Commit::root(&fs).unwrap();
+        let left = root
+            .make_child(&fs, &Tree::empty().write(&fs, &["a"], vec![1]).unwrap())
+            .unwrap();
+        let right = root
+            .make_child(&fs, &Tree::empty().write(&fs, &["b"], vec![2]).unwrap())
+            .unwrap();
+        let tree = Tree::merge(
+            &fs,
+            &root.tree(&fs).unwrap(),
+            &left.tree(&fs).unwrap(),
+            &right.tree(&fs).unwrap(),
+        )
+        .unwrap()
+        .tree()
+        .unwrap();
+
+        let merge = Commit::make_merge(&fs, &[left.clone(), right.clone()], &tree).unwrap();
+        let merge = Commit::for_hash(merge.hash(&fs).unwrap());
+        let parents: Vec<Hash> = merge
+            .parents(&fs)
+            .unwrap()
+            .iter()
+            .map(|p| p.hash(&fs).unwrap().clone())
+            .collect();
+        assert_eq!(
+            parents,
+            vec![
+                left.hash(&fs).unwrap().clone(),
+                right.hash(&fs).unwrap().clone()
+            ]
+        );
+        let tree = merge.tree(&fs).unwrap();
+        assert_eq!(tree.read(&fs, &["a"]).unwrap(), Some(vec![1]));
+        assert_eq!(tree.read(&fs, &["b"]).unwrap(), Some(vec![2]));
+
+        assert!(Commit::make_merge(&fs, &[left], &tree).is_err());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let storage = LocalStorage::new();
+        let fs = FileSystem::new(Box::new(storage));
+
+        let root = Commit::root(&fs).unwrap();
+        assert_eq!(root.metadata(&fs).unwrap(), Metadata::default());
+
+        let tree = Tree::empty().write(&fs, &["a"], vec![1]).unwrap();
+        let cmt = Commit::builder(&[root.clone()], &tree)
+            .author("node-7")
+            .timestamp(1_500_000_000)
+            .message("add a")
+            .annotation("ticket", "123")
+            .build(&fs)
+            .unwrap();
+
+        // hash it and re-retrieve it
+        let cmt = Commit::for_hash(cmt.hash(&fs).unwrap());
+        let metadata = cmt.metadata(&fs).unwrap();
+        assert_eq!(metadata.author, "node-7");
+        assert_eq!(metadata.timestamp, 1_500_000_000);
+        assert_eq!(metadata.message, "add a");
+        assert_eq!(metadata.annotations.get("ticket"), Some(&"123".to_string()));
+        assert_eq!(cmt.parents(&fs).unwrap().len(), 1);
+        assert_eq!(
+            cmt.tree(&fs).unwrap().hash(&fs).unwrap(),
+            tree.hash(&fs).unwrap()
+        );
+
+        // metadata is part of the hash
+        let other = Commit::builder(&[root.clone()], &tree)
+            .message("add a")
+            .build(&fs)
+            .unwrap();
+        assert_ne!(other.hash(&fs).unwrap(), cmt.hash(&fs).unwrap());
+    }
+
+    #[test]
+    fn test_versioned_encoding() {
+        let storage = LocalStorage::new();
+        let fs = FileSystem::new(Box::new(storage));
+        let tree = Tree::empty().hash(&fs).unwrap().clone();
+
+        // a commit stored before metadata existed still decodes, with empty metadata; hashing
+        // the root commit stores it
+        Commit::root(&fs).unwrap().hash(&fs).unwrap();
+        let old = Commit::for_hash(&Hash::from_hex(ROOT_HASH));
+        assert_eq!(old.metadata(&fs).unwrap(), Metadata::default());
+        assert_eq!(old.parents(&fs).unwrap().len(), 0);
+
+        // a version-1 commit decodes
+        let metadata = Metadata {
+            author: "node-1".to_string(),
+            timestamp: 1_500_000_000,
+            message: "a message".to_string(),
+            annotations: vec![("a".to_string(), "1".to_string())]
+                .into_iter()
+                .collect(),
+        };
+        let (version, encoded) = metadata.clone().encode().unwrap();
+        assert_eq!(version, 1);
+        let content = Content::AnnotatedCommit {
+            parents: vec![Hash::from_hex(ROOT_HASH)],
+            tree: tree.clone(),
+            version,
+            metadata: encoded,
+        };
+        let new = Commit::for_hash(&content.store_in(&fs).unwrap());
+        assert_eq!(new.metadata(&fs).unwrap(), metadata);
+        assert_eq!(new.parents(&fs).unwrap().len(), 1);
+
+        // an unknown version is an error, but the rest of the commit is still usable
+        let content = Content::AnnotatedCommit {
+            parents: vec![],
+            tree,
+            version: 99,
+            metadata: vec![],
+        };
+        let future = Commit::for_hash(&content.store_in(&fs).unwrap());
+        assert!(future.metadata(&fs).is_err());
+        assert_eq!(future.parents(&fs).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_builder_without_metadata() {
+        let storage = LocalStorage::new();
+        let fs = FileSystem::new(Box::new(storage));
+
+        // a commit without metadata has the same hash as one made with make_child
+        let root = Commit::root(&fs).unwrap();
+        let tree = Tree::empty().write(&fs, &["a"], vec![1]).unwrap();
+        let built = Commit::builder(&[root.clone()], &tree).build(&fs).unwrap();
+        let child = root.make_child(&fs, &tree).unwrap();
+        assert_eq!(built.hash(&fs).unwrap(), child.hash(&fs).unwrap());
+    }
 }
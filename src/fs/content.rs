@@ -20,6 +20,43 @@ pub enum Content {
         data: Option<Vec<u8>>,
         children: HashMap<String, Hash>,
     },
+    /// A tree whose data is too large to store inline, and is instead stored as a sequence of
+    /// chunks listed in a manifest.
+    ChunkedTree {
+        manifest: Hash,
+        children: HashMap<String, Hash>,
+    },
+    /// A manifest of chunks which, concatenated, make up a large value.  Each chunk is stored
+    /// directly in the CAS, without any encoding.
+    Manifest {
+        chunks: Vec<Hash>,
+    },
+    /// A commit with metadata.  The metadata is encoded separately, in the format given by
+    /// `version`, so that the format can change without adding variants here.  Commits without
+    /// any metadata are still stored as `Commit`, so that their hashes, such as that of the root
+    /// commit, do not change.
+    AnnotatedCommit {
+        parents: Vec<Hash>,
+        tree: Hash,
+        version: u32,
+        metadata: Vec<u8>,
+    },
+}
+
+/// Encode a map of tree children, sorted by name
+fn encode_children<S: Encoder>(
+    children: &HashMap<String, Hash>,
+    s: &mut S,
+) -> Result<(), S::Error> {
+    s.emit_map(children.len(), |s| {
+        let mut by_name: Vec<(&String, &Hash)> = children.iter().collect();
+        by_name.sort();
+        for (i, (n, t)) in by_name.iter().enumerate() {
+            s.emit_map_elt_key(i, |s| n.encode(s))?;
+            s.emit_map_elt_key(i, |s| t.encode(s))?;
+        }
+        Ok(())
+    })
 }
 
 impl Encodable for Content {
@@ -36,17 +73,32 @@ impl Encodable for Content {
             }),
             Content::Tree { data, children } => s.emit_enum_struct_variant("Tree", 1, 2, |s| {
                 s.emit_enum_struct_variant_field("data", 0, |s| data.encode(s))?;
-                s.emit_enum_struct_variant_field("children", 1, |s| {
-                    s.emit_map(children.len(), |s| {
-                        let mut by_name: Vec<(&String, &Hash)> = children.iter().collect();
-                        by_name.sort();
-                        for (i, (n, t)) in by_name.iter().enumerate() {
-                            s.emit_map_elt_key(i, |s| n.encode(s))?;
-                            s.emit_map_elt_key(i, |s| t.encode(s))?;
-                        }
-                        Ok(())
-                    })
-                })?;
+                s.emit_enum_struct_variant_field("children", 1, |s| encode_children(children, s))?;
+                Ok(())
+            }),
+            Content::ChunkedTree { manifest, children } => {
+                s.emit_enum_struct_variant("ChunkedTree", 2, 2, |s| {
+                    s.emit_enum_struct_variant_field("manifest", 0, |s| manifest.encode(s))?;
+                    s.emit_enum_struct_variant_field("children", 1, |s| {
+                        encode_children(children, s)
+                    })?;
+                    Ok(())
+                })
+            }
+            Content::Manifest { chunks } => s.emit_enum_struct_variant("Manifest", 3, 1, |s| {
+                s.emit_enum_struct_variant_field("chunks", 0, |s| chunks.encode(s))?;
+                Ok(())
+            }),
+            Content::AnnotatedCommit {
+                parents,
+                tree,
+                version,
+                metadata,
+            } => s.emit_enum_struct_variant("AnnotatedCommit", 4, 4, |s| {
+                s.emit_enum_struct_variant_field("parents", 0, |s| parents.encode(s))?;
+                s.emit_enum_struct_variant_field("tree", 1, |s| tree.encode(s))?;
+                s.emit_enum_struct_variant_field("version", 2, |s| version.encode(s))?;
+                s.emit_enum_struct_variant_field("metadata", 3, |s| metadata.encode(s))?;
                 Ok(())
             }),
         })
@@ -85,4 +137,40 @@ mod test {
         let content2 = Content::retrieve_from(&fs, &hash).unwrap();
         assert_eq!(content, content2);
     }
+
+    #[test]
+    fn test_chunked_round_trip() {
+        let storage = LocalStorage::new();
+        let fs = FileSystem::new(Box::new(storage));
+        let mut children = HashMap::new();
+        children.insert("b".to_string(), Hash::from_hex(EMPTY_TREE_HASH));
+        children.insert("a".to_string(), Hash::from_hex(EMPTY_TREE_HASH));
+        let manifest = Content::Manifest {
+            chunks: vec![Hash::from_hex("0123"), Hash::from_hex("4567")],
+        };
+        let content = Content::ChunkedTree {
+            manifest: manifest.store_in(&fs).unwrap(),
+            children,
+        };
+
+        let hash = content.store_in(&fs).unwrap();
+        let content2 = Content::retrieve_from(&fs, &hash).unwrap();
+        assert_eq!(content, content2);
+    }
+
+    #[test]
+    fn test_annotated_commit_round_trip() {
+        let storage = LocalStorage::new();
+        let fs = FileSystem::new(Box::new(storage));
+        let content = Content::AnnotatedCommit {
+            parents: vec![Hash::from_hex("0123")],
+            tree: Hash::from_hex(EMPTY_TREE_HASH),
+            version: 1,
+            metadata: vec![1, 2, 3],
+        };
+
+        let hash = content.store_in(&fs).unwrap();
+        let content2 = Content::retrieve_from(&fs, &hash).unwrap();
+        assert_eq!(content, content2);
+    }
 }
@@ -0,0 +1,194 @@
+use super::fs::FileSystem;
+use super::tree::Tree;
+use crate::cas::Hash;
+use failure::Fallible;
+use std::collections::BTreeSet;
+
+/// A Change is a difference in the data at a path between two trees.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Data was added at a path that had none
+    Added { path: Vec<String>, data: Vec<u8> },
+
+    /// Data was removed from a path
+    Removed { path: Vec<String>, data: Vec<u8> },
+
+    /// The data at a path changed
+    Modified {
+        path: Vec<String>,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+}
+
+impl Change {
+    /// Get the path at which this change occurred
+    pub fn path(&self) -> &[String] {
+        match self {
+            Change::Added { path, .. }
+            | Change::Removed { path, .. }
+            | Change::Modified { path, .. } => path,
+        }
+    }
+}
+
+impl Tree {
+    /// Find the differences between this tree and `other`, treating this tree as the old version.
+    /// Changes are returned sorted by path.
+    ///
+    /// Subtrees with equal hashes are skipped without loading them, so diffing two large trees
+    /// that differ at one path only loads the nodes along that path.
+    pub fn diff(&self, fs: &FileSystem, other: &Tree) -> Fallible<Vec<Change>> {
+        let mut differ = Differ {
+            fs,
+            path: vec![],
+            changes: vec![],
+        };
+        differ.diff(Some(self.hash(fs)?), Some(other.hash(fs)?))?;
+        Ok(differ.changes)
+    }
+}
+
+struct Differ<'a> {
+    fs: &'a FileSystem,
+
+    /// The path to the subtrees currently being compared
+    path: Vec<String>,
+
+    changes: Vec<Change>,
+}
+
+impl<'a> Differ<'a> {
+    /// Compare the subtrees at the current path.  A None input means there is no subtree at this
+    /// path on that side.
+    fn diff(&mut self, old: Option<&Hash>, new: Option<&Hash>) -> Fallible<()> {
+        if old == new {
+            return Ok(());
+        }
+
+        let (old_data, old_children) = Tree::content_for_hash(self.fs, old)?;
+        let (new_data, new_children) = Tree::content_for_hash(self.fs, new)?;
+
+        if old_data != new_data {
+            let fs = self.fs;
+            let path = self.path.clone();
+            self.changes.push(match (old_data, new_data) {
+                (None, Some(data)) => Change::Added {
+                    path,
+                    data: data.load(fs)?,
+                },
+                (Some(data), None) => Change::Removed {
+                    path,
+                    data: data.load(fs)?,
+                },
+                (Some(old), Some(new)) => Change::Modified {
+                    path,
+                    old: old.load(fs)?,
+                    new: new.load(fs)?,
+                },
+                (None, None) => unreachable!(),
+            });
+        }
+
+        let names: BTreeSet<&String> = old_children.keys().chain(new_children.keys()).collect();
+        for name in names {
+            self.path.push(name.clone());
+            self.diff(old_children.get(name), new_children.get(name))?;
+            self.path.pop();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cas::LocalStorage;
+    use crate::fs::tree::test::{retain_only, tree};
+
+    fn path(elts: &[&str]) -> Vec<String> {
+        elts.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn identical() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let old = tree(&fs, &[(&["a"], b"1")]);
+        let new = tree(&fs, &[(&["a"], b"1")]);
+        assert_eq!(old.diff(&fs, &new).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn changes() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+
+        let old = tree(
+            &fs,
+            &[(&["a"], b"1"), (&["b", "c"], b"2"), (&["b", "d"], b"3")],
+        );
+        let new = old
+            .write(&fs, &["a"], b"4".to_vec())
+            .unwrap()
+            .remove(&fs, &["b", "c"])
+            .unwrap()
+            .write(&fs, &["b"], b"5".to_vec())
+            .unwrap()
+            .write(&fs, &["e", "f"], b"6".to_vec())
+            .unwrap();
+
+        assert_eq!(
+            old.diff(&fs, &new).unwrap(),
+            vec![
+                Change::Modified {
+                    path: path(&["a"]),
+                    old: b"1".to_vec(),
+                    new: b"4".to_vec(),
+                },
+                Change::Added {
+                    path: path(&["b"]),
+                    data: b"5".to_vec(),
+                },
+                Change::Removed {
+                    path: path(&["b", "c"]),
+                    data: b"2".to_vec(),
+                },
+                Change::Added {
+                    path: path(&["e", "f"]),
+                    data: b"6".to_vec(),
+                },
+            ]
+        );
+
+        let reversed = new.diff(&fs, &old).unwrap();
+        assert_eq!(reversed.len(), 4);
+        assert_eq!(reversed[3].path(), &path(&["e", "f"])[..]);
+    }
+
+    #[test]
+    fn equal_subtrees_not_loaded() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+
+        let old = tree(&fs, &[(&["big", "x"], b"1"), (&["small"], b"2")]);
+        let new = old.write(&fs, &["small"], b"3".to_vec()).unwrap();
+        let old = Tree::for_hash(old.hash(&fs).unwrap());
+        let new = Tree::for_hash(new.hash(&fs).unwrap());
+
+        // remove everything but the roots and "small" from storage
+        let mut keep = vec![];
+        for tree in &[&old, &new] {
+            keep.push(tree.hash(&fs).unwrap().clone());
+            let small = tree.child(&fs, "small").unwrap().unwrap();
+            keep.push(small.hash(&fs).unwrap().clone());
+        }
+        retain_only(&fs, &keep);
+
+        assert_eq!(
+            old.diff(&fs, &new).unwrap(),
+            vec![Change::Modified {
+                path: path(&["small"]),
+                old: b"2".to_vec(),
+                new: b"3".to_vec(),
+            }]
+        );
+    }
+}
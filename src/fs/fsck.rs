@@ -0,0 +1,252 @@
+use super::content::Content;
+use super::fs::FileSystem;
+use super::lazy::LazyContent;
+use crate::cas::Hash;
+use std::collections::HashSet;
+use std::fmt;
+
+/// The kind of object expected at a hash, based on how it was referenced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectKind {
+    Commit,
+    Tree,
+    Manifest,
+    Chunk,
+}
+
+/// A Problem is an inconsistency found by `FileSystem::fsck`.  Each problem records the object
+/// that referred to the problematic object, or None if it was one of the starting commits.
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// The referenced object is not in storage
+    Dangling {
+        hash: Hash,
+        kind: ObjectKind,
+        referrer: Option<Hash>,
+    },
+
+    /// The referenced object could not be decoded
+    Undecodable {
+        hash: Hash,
+        kind: ObjectKind,
+        referrer: Option<Hash>,
+        error: String,
+    },
+
+    /// The referenced object is not of the expected kind, such as a tree referenced as a commit
+    WrongKind {
+        hash: Hash,
+        expected: ObjectKind,
+        referrer: Option<Hash>,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let referrer = match self {
+            Problem::Dangling { hash, kind, .. } => {
+                write!(f, "dangling {:?} reference to {}", kind, hash)?;
+                self.referrer()
+            }
+            Problem::Undecodable {
+                hash, kind, error, ..
+            } => {
+                write!(f, "undecodable {:?} {}: {}", kind, hash, error)?;
+                self.referrer()
+            }
+            Problem::WrongKind { hash, expected, .. } => {
+                write!(f, "{} is not a {:?}", hash, expected)?;
+                self.referrer()
+            }
+        };
+        if let Some(referrer) = referrer {
+            write!(f, " (referenced from {})", referrer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Problem {
+    /// Get the object that referred to the problematic object, if any
+    pub fn referrer(&self) -> Option<&Hash> {
+        match self {
+            Problem::Dangling { referrer, .. }
+            | Problem::Undecodable { referrer, .. }
+            | Problem::WrongKind { referrer, .. } => referrer.as_ref(),
+        }
+    }
+}
+
+impl FileSystem {
+    /// Check the consistency of all objects reachable from the given commits, returning a list
+    /// of the problems found.  This walks all parent commits, trees, and chunks, visiting each
+    /// object once.  Objects that cannot be read from storage for any reason are reported as
+    /// dangling.
+    pub fn fsck(&self, commits: &[Hash]) -> Vec<Problem> {
+        let mut problems = vec![];
+        let mut seen = HashSet::new();
+        let mut pending: Vec<(Hash, ObjectKind, Option<Hash>)> = commits
+            .iter()
+            .map(|h| (h.clone(), ObjectKind::Commit, None))
+            .collect();
+
+        while let Some((hash, kind, referrer)) = pending.pop() {
+            if !seen.insert((hash.clone(), kind)) {
+                continue;
+            }
+
+            if !self.storage.contains(&hash).unwrap_or(false) {
+                problems.push(Problem::Dangling {
+                    hash,
+                    kind,
+                    referrer,
+                });
+                continue;
+            }
+
+            // chunks are uninterpreted bytes, so it is enough that they exist
+            if kind == ObjectKind::Chunk {
+                continue;
+            }
+            let content = match Content::retrieve_from(self, &hash) {
+                Ok(content) => content,
+                Err(e) => {
+                    problems.push(Problem::Undecodable {
+                        hash,
+                        kind,
+                        referrer,
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            let mut refer = |h: &Hash, k| pending.push((h.clone(), k, Some(hash.clone())));
+            match (kind, &content) {
+                (ObjectKind::Commit, Content::Commit { parents, tree })
+                | (ObjectKind::Commit, Content::AnnotatedCommit { parents, tree, .. }) => {
+                    for parent in parents {
+                        refer(parent, ObjectKind::Commit);
+                    }
+                    refer(tree, ObjectKind::Tree);
+                }
+                (ObjectKind::Tree, Content::Tree { children, .. }) => {
+                    for child in children.values() {
+                        refer(child, ObjectKind::Tree);
+                    }
+                }
+                (ObjectKind::Tree, Content::ChunkedTree { manifest, children }) => {
+                    refer(manifest, ObjectKind::Manifest);
+                    for child in children.values() {
+                        refer(child, ObjectKind::Tree);
+                    }
+                }
+                (ObjectKind::Manifest, Content::Manifest { chunks }) => {
+                    for chunk in chunks {
+                        refer(chunk, ObjectKind::Chunk);
+                    }
+                }
+                _ => problems.push(Problem::WrongKind {
+                    hash: hash.clone(),
+                    expected: kind,
+                    referrer,
+                }),
+            }
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cas::LocalStorage;
+    use crate::fs::chunk::test::random_data;
+    use crate::fs::{Commit, Tree};
+    use std::collections::HashMap;
+
+    #[test]
+    fn clean() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let tree = Tree::empty()
+            .write(&fs, &["a", "b"], vec![1])
+            .unwrap()
+            .write(&fs, &["big"], random_data(1, 100 * 1024))
+            .unwrap();
+        let cmt = Commit::root(&fs).unwrap().make_child(&fs, &tree).unwrap();
+        let hash = cmt.hash(&fs).unwrap().clone();
+
+        assert_eq!(fs.fsck(&[hash]), vec![]);
+    }
+
+    #[test]
+    fn dangling() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let missing = Hash::from_hex("0123");
+        let commit = Content::Commit {
+            parents: vec![missing.clone()],
+            tree: Tree::empty().hash(&fs).unwrap().clone(),
+        };
+        let hash = commit.store_in(&fs).unwrap();
+
+        assert_eq!(
+            fs.fsck(&[hash.clone()]),
+            vec![Problem::Dangling {
+                hash: missing,
+                kind: ObjectKind::Commit,
+                referrer: Some(hash),
+            }]
+        );
+    }
+
+    #[test]
+    fn undecodable() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let garbage = fs.storage.store(vec![99, 0, 0, 0, 1, 2]).unwrap();
+        let mut children = HashMap::new();
+        children.insert("x".to_string(), garbage.clone());
+        let tree = Content::Tree {
+            data: None,
+            children,
+        };
+        let commit = Content::Commit {
+            parents: vec![],
+            tree: tree.store_in(&fs).unwrap(),
+        };
+        let hash = commit.store_in(&fs).unwrap();
+
+        let problems = fs.fsck(&[hash]);
+        assert_eq!(problems.len(), 1);
+        match &problems[0] {
+            Problem::Undecodable { hash, kind, .. } => {
+                assert_eq!(hash, &garbage);
+                assert_eq!(kind, &ObjectKind::Tree);
+            }
+            p => panic!("unexpected {:?}", p),
+        }
+    }
+
+    #[test]
+    fn tree_as_commit() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let tree = Tree::empty().write(&fs, &["a"], vec![1]).unwrap();
+        let tree_hash = tree.hash(&fs).unwrap().clone();
+
+        assert_eq!(
+            fs.fsck(&[tree_hash.clone()]),
+            vec![Problem::WrongKind {
+                hash: tree_hash.clone(),
+                expected: ObjectKind::Commit,
+                referrer: None,
+            }]
+        );
+        assert_eq!(
+            format!("{}", fs.fsck(&[tree_hash.clone()])[0]),
+            format!("{} is not a Commit", tree_hash)
+        );
+
+        // and using the tree as a commit is an error, not a panic
+        assert!(Commit::for_hash(&tree_hash).parents(&fs).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use super::commit::Commit;
+use super::content::Content;
+use super::fs::FileSystem;
+use super::fsck::ObjectKind;
+use super::lazy::LazyContent;
+use crate::cas::{GarbageCycle, Hash};
+use failure::{bail, Fallible};
+use std::collections::HashSet;
+
+impl FileSystem {
+    /// Collect garbage, keeping only the given commits and the objects reachable from them: their
+    /// parent commits, trees, and chunks.  Everything else in storage, such as trees written but
+    /// never committed, is reclaimed.
+    ///
+    /// Each object is touched only once, so subtrees shared between commits are skipped once they
+    /// have been touched in this cycle.  If any reachable object cannot be touched or read, the
+    /// cycle is abandoned without collecting anything, and the error is returned.
+    pub fn collect_garbage(&self, roots: &[Commit]) -> Fallible<()> {
+        let cycle = GarbageCycle::new(&*self.storage)?;
+        match self.touch_reachable(roots) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                cycle.abandon();
+                Err(e)
+            }
+        }
+    }
+
+    fn touch_reachable(&self, roots: &[Commit]) -> Fallible<()> {
+        let mut seen = HashSet::new();
+        let mut pending = vec![];
+        for root in roots {
+            // hashing the root stores it if necessary, which marks it as part of this cycle
+            pending.push((root.hash(self)?.clone(), ObjectKind::Commit));
+        }
+
+        while let Some((hash, kind)) = pending.pop() {
+            if !seen.insert(hash.clone()) {
+                continue;
+            }
+            self.storage.touch(&hash)?;
+
+            match (kind, Content::retrieve_from(self, &hash)?) {
+                (ObjectKind::Commit, Content::Commit { parents, tree })
+                | (ObjectKind::Commit, Content::AnnotatedCommit { parents, tree, .. }) => {
+                    pending.extend(parents.into_iter().map(|h| (h, ObjectKind::Commit)));
+                    pending.push((tree, ObjectKind::Tree));
+                }
+                (ObjectKind::Tree, Content::Tree { children, .. }) => {
+                    pending.extend(children.values().map(|h| (h.clone(), ObjectKind::Tree)));
+                }
+                (ObjectKind::Tree, Content::ChunkedTree { manifest, children }) => {
+                    pending.push((manifest, ObjectKind::Manifest));
+                    pending.extend(children.values().map(|h| (h.clone(), ObjectKind::Tree)));
+                }
+                (ObjectKind::Manifest, Content::Manifest { chunks }) => {
+                    // chunks are uninterpreted bytes, so they need only be touched
+                    let chunks: Vec<Hash> = chunks
+                        .into_iter()
+                        .filter(|h| seen.insert(h.clone()))
+                        .collect();
+                    self.storage.touch_many(&chunks)?;
+                }
+                _ => bail!("{:?} is not a {:?}", hash, kind),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cas::LocalStorage;
+    use crate::fs::chunk::test::random_data;
+    use crate::fs::Tree;
+
+    #[test]
+    fn keeps_reachable() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let root = Commit::root(&fs).unwrap();
+        let tree = Tree::empty()
+            .write(&fs, &["a", "b"], vec![1])
+            .unwrap()
+            .write(&fs, &["big"], random_data(1, 200 * 1024))
+            .unwrap();
+        let cmt = root.make_child(&fs, &tree).unwrap();
+        let tree = cmt.tree(&fs).unwrap().write(&fs, &["c"], vec![2]).unwrap();
+        let cmt = cmt.make_child(&fs, &tree).unwrap();
+        let hash = cmt.hash(&fs).unwrap().clone();
+
+        // an abandoned write, never committed
+        let abandoned = tree.write(&fs, &["d"], vec![3]).unwrap();
+        let abandoned = abandoned.hash(&fs).unwrap().clone();
+
+        fs.collect_garbage(&[cmt]).unwrap();
+
+        assert!(!fs.storage.contains(&abandoned).unwrap());
+        assert_eq!(fs.fsck(&[hash.clone()]), vec![]);
+        let tree = Commit::for_hash(&hash).tree(&fs).unwrap();
+        assert_eq!(
+            tree.read(&fs, &["big"]).unwrap(),
+            Some(random_data(1, 200 * 1024))
+        );
+    }
+
+    #[test]
+    fn abandons_on_error() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let garbage = fs.storage.store(vec![1, 2, 3]).unwrap();
+        let commit = Content::Commit {
+            parents: vec![Hash::from_hex("0123")],
+            tree: Tree::empty().hash(&fs).unwrap().clone(),
+        };
+        let hash = commit.store_in(&fs).unwrap();
+
+        assert!(fs.collect_garbage(&[Commit::for_hash(&hash)]).is_err());
+        assert!(fs.storage.contains(&garbage).unwrap());
+
+        // a later, successful cycle still collects the garbage
+        let root = Commit::root(&fs).unwrap();
+        fs.collect_garbage(&[root.clone()]).unwrap();
+        assert!(!fs.storage.contains(&garbage).unwrap());
+        assert!(!fs.storage.contains(&hash).unwrap());
+        assert!(fs.storage.contains(root.hash(&fs).unwrap()).unwrap());
+
+        let stats = fs.storage.stats().unwrap();
+        assert_eq!(stats.garbage_generation + 1, stats.cur_generation);
+        assert_eq!(stats.last_gc.unwrap().objects_reclaimed, 2);
+    }
+}
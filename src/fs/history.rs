@@ -0,0 +1,523 @@
+use super::commit::Commit;
+use super::fs::FileSystem;
+use super::tree::Tree;
+use crate::cas::Hash;
+use failure::Fallible;
+use std::cmp::{min, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+
+/// The order in which `Ancestors` visits commits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkOrder {
+    /// Every commit is visited before any of its parents.  This requires reading the whole history
+    /// (up to the depth limit) before the first commit is returned.
+    Topological,
+
+    /// Commits are visited newest first, by timestamp.  This reads history lazily, but a commit
+    /// with a timestamp later than one of its children (due to clock skew) may be visited after
+    /// that child's other ancestors.
+    Date,
+}
+
+/// Ancestors is an iterator over a commit and its ancestors, created with `Commit::ancestors`.
+/// Each commit is visited once, even if it is reachable along several paths through merge
+/// commits.  If an error occurs, it is returned and iteration ends.
+pub struct Ancestors<'a> {
+    fs: &'a FileSystem,
+    start: Commit,
+    order: WalkOrder,
+    max_depth: Option<usize>,
+    path: Option<Vec<String>>,
+    walk: Option<Walk>,
+    failed: bool,
+}
+
+enum Walk {
+    /// The remaining commits, in reverse order
+    Topological(Vec<Hash>),
+
+    Date {
+        /// Pending commits by (timestamp, insertion order)
+        queue: BinaryHeap<(u64, Reverse<u64>, Hash)>,
+
+        /// The commit and depth for each hash in the queue
+        pending: HashMap<Hash, (Commit, usize)>,
+
+        /// All hashes that have ever been queued
+        seen: HashSet<Hash>,
+
+        /// A counter for insertion order, so that commits with equal timestamps are visited in
+        /// the order they were found
+        counter: u64,
+    },
+}
+
+impl Commit {
+    /// Iterate over this commit and its ancestors, in topological order.  Use the methods of
+    /// `Ancestors` to change the order or limit which commits are visited.
+    pub fn ancestors<'a>(&self, fs: &'a FileSystem) -> Ancestors<'a> {
+        Ancestors {
+            fs,
+            start: self.clone(),
+            order: WalkOrder::Topological,
+            max_depth: None,
+            path: None,
+            walk: None,
+            failed: false,
+        }
+    }
+
+    /// Determine whether commit `a` is an ancestor of commit `b`.  A commit is considered an
+    /// ancestor of itself.  History is walked in date order from `b`, stopping when `a` is found.
+    /// If `a` is not an ancestor, this reads all of `b`'s history.  The walk cannot stop once
+    /// timestamps fall below that of `a`, since clock skew may give a commit between `a` and `b`
+    /// an earlier timestamp than `a`.
+    pub fn is_ancestor(fs: &FileSystem, a: &Commit, b: &Commit) -> Fallible<bool> {
+        let a = a.hash(fs)?;
+        for commit in b.ancestors(fs).order(WalkOrder::Date) {
+            if commit?.hash(fs)? == a {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Find the best common ancestors of commits `a` and `b`: the commits that are ancestors of
+    /// both, and are not ancestors of any other such commit.  This is usually a single commit,
+    /// but criss-cross merges can produce several, and commits with unrelated histories have
+    /// none.  If one commit is an ancestor of the other, it is the merge base.  This reads the
+    /// full history of both commits.
+    pub fn merge_base(fs: &FileSystem, a: &Commit, b: &Commit) -> Fallible<Vec<Commit>> {
+        let mut of_a = HashSet::new();
+        for commit in a.ancestors(fs) {
+            of_a.insert(commit?.hash(fs)?.clone());
+        }
+
+        // walk b's history with children before parents, so that by the time a commit is visited
+        // we know whether it is an ancestor of a common ancestor already found
+        let mut bases = vec![];
+        let mut covered = HashSet::new();
+        for commit in b.ancestors(fs) {
+            let commit = commit?;
+            let hash = commit.hash(fs)?;
+            if !covered.contains(hash) {
+                if !of_a.contains(hash) {
+                    continue;
+                }
+                bases.push(commit.clone());
+            }
+            covered.extend(commit.parent_hashes(fs)?.iter().cloned());
+        }
+        Ok(bases)
+    }
+}
+
+impl<'a> Ancestors<'a> {
+    /// Set the order in which commits are visited
+    pub fn order(mut self, order: WalkOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Only visit commits at most `max_depth` generations before the starting commit, so a depth
+    /// of 0 visits only the starting commit.  In date order, a commit's depth is counted along the
+    /// shortest path found before it is visited.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Only return commits that changed the data at or beneath the given path.  A commit changed
+    /// the path if the subtree there differs from that of each of its parents, so a merge commit
+    /// that took the path from one of its parents is not included.  A root commit changed the path
+    /// if the path exists.
+    pub fn path(mut self, path: &[&str]) -> Self {
+        self.path = Some(path.iter().map(|e| e.to_string()).collect());
+        self
+    }
+
+    fn begin(&self) -> Fallible<Walk> {
+        let start = self.start.hash(self.fs)?.clone();
+        match self.order {
+            WalkOrder::Topological => {
+                let mut sorted = self.sort_topologically(start)?;
+                sorted.reverse();
+                Ok(Walk::Topological(sorted))
+            }
+            WalkOrder::Date => {
+                let mut queue = BinaryHeap::new();
+                queue.push((0, Reverse(0), start.clone()));
+                let mut pending = HashMap::new();
+                pending.insert(start.clone(), (self.start.clone(), 0));
+                let mut seen = HashSet::new();
+                seen.insert(start);
+                Ok(Walk::Date {
+                    queue,
+                    pending,
+                    seen,
+                    counter: 1,
+                })
+            }
+        }
+    }
+
+    /// Read the history from `start` and sort it so that each commit appears before its parents
+    fn sort_topologically(&self, start: Hash) -> Fallible<Vec<Hash>> {
+        // read the graph breadth-first, so that each commit is found at its shortest depth
+        let mut parents: HashMap<Hash, Vec<Hash>> = HashMap::new();
+        let mut seen = HashSet::new();
+        seen.insert(start.clone());
+        let mut queue = VecDeque::new();
+        queue.push_back((start.clone(), 0));
+        while let Some((hash, depth)) = queue.pop_front() {
+            let mut hash_parents = Commit::for_hash(&hash).parent_hashes(self.fs)?.to_vec();
+            if within_depth(self.max_depth, depth) {
+                for parent in &hash_parents {
+                    if seen.insert(parent.clone()) {
+                        queue.push_back((parent.clone(), depth + 1));
+                    }
+                }
+            } else {
+                // at the depth limit, keep only the edges to parents already in the walk, which
+                // were all found at a lesser depth, so that they still sort after this commit
+                hash_parents.retain(|parent| seen.contains(parent));
+            }
+            parents.insert(hash, hash_parents);
+        }
+
+        // count the children of each commit, then emit each commit once all of its children
+        // have been emitted
+        let mut children: HashMap<&Hash, usize> = HashMap::new();
+        for parent in parents.values().flatten() {
+            *children.entry(parent).or_insert(0) += 1;
+        }
+        let mut sorted = vec![];
+        let mut ready = vec![&start];
+        while let Some(hash) = ready.pop() {
+            sorted.push(hash.clone());
+            // push in reverse so that the first parent's history is visited first
+            for parent in parents[hash].iter().rev() {
+                let count = children.get_mut(parent).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    ready.push(parent);
+                }
+            }
+        }
+        Ok(sorted)
+    }
+
+    /// Get the next commit in the walk, without filtering by path
+    fn next_commit(&mut self) -> Fallible<Option<Commit>> {
+        if self.walk.is_none() {
+            self.walk = Some(self.begin()?);
+        }
+        let fs = self.fs;
+        let max_depth = self.max_depth;
+        match self.walk.as_mut().unwrap() {
+            Walk::Topological(remaining) => Ok(remaining.pop().map(|h| Commit::for_hash(&h))),
+            Walk::Date {
+                queue,
+                pending,
+                seen,
+                counter,
+            } => {
+                let hash = match queue.pop() {
+                    Some((_, _, hash)) => hash,
+                    None => return Ok(None),
+                };
+                let (commit, depth) = pending.remove(&hash).unwrap();
+                if within_depth(max_depth, depth) {
+                    for parent in commit.parent_hashes(fs)? {
+                        if let Some((_, parent_depth)) = pending.get_mut(parent) {
+                            *parent_depth = min(*parent_depth, depth + 1);
+                        } else if seen.insert(parent.clone()) {
+                            let parent_commit = Commit::for_hash(parent);
+                            let timestamp = parent_commit.timestamp(fs)?;
+                            queue.push((timestamp, Reverse(*counter), parent.clone()));
+                            *counter += 1;
+                            pending.insert(parent.clone(), (parent_commit, depth + 1));
+                        }
+                    }
+                }
+                Ok(Some(commit))
+            }
+        }
+    }
+
+    /// Determine whether the commit changed the subtree at `path`
+    fn changed(&self, commit: &Commit, path: &[String]) -> Fallible<bool> {
+        let subtree = subtree_hash(self.fs, commit.tree(self.fs)?, path)?;
+        let parents = commit.parents(self.fs)?;
+        if parents.is_empty() {
+            return Ok(subtree.is_some());
+        }
+        for parent in parents {
+            if subtree_hash(self.fs, parent.tree(self.fs)?, path)? == subtree {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<'a> Iterator for Ancestors<'a> {
+    type Item = Fallible<Commit>;
+
+    fn next(&mut self) -> Option<Fallible<Commit>> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            let result = match self.next_commit() {
+                Ok(Some(commit)) => match self.path {
+                    Some(ref path) => match self.changed(&commit, path) {
+                        Ok(true) => Ok(commit),
+                        Ok(false) => continue,
+                        Err(e) => Err(e),
+                    },
+                    None => Ok(commit),
+                },
+                Ok(None) => return None,
+                Err(e) => Err(e),
+            };
+            if result.is_err() {
+                self.failed = true;
+            }
+            return Some(result);
+        }
+    }
+}
+
+/// Determine whether the parents of a commit at the given depth should be visited
+fn within_depth(max_depth: Option<usize>, depth: usize) -> bool {
+    match max_depth {
+        Some(max) => depth < max,
+        None => true,
+    }
+}
+
+/// Get the hash of the subtree at the given path, if it exists
+fn subtree_hash(fs: &FileSystem, mut tree: Tree, path: &[String]) -> Fallible<Option<Hash>> {
+    for elt in path {
+        tree = match tree.child(fs, elt)? {
+            Some(child) => child,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(tree.hash(fs)?.clone()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cas::LocalStorage;
+    use crate::fs::content::Content;
+    use crate::fs::lazy::LazyContent;
+
+    /// Build a history with a merge:
+    ///
+    /// ```text
+    /// root -- a -- b -- m
+    ///           \      /
+    ///            `- c -'
+    /// ```
+    ///
+    /// `a` and `c` write `x`, while `b` writes `y`.  Timestamps are in order root, a, b, c, m.
+    fn history(fs: &FileSystem) -> HashMap<&'static str, Commit> {
+        let root = Commit::root(fs).unwrap();
+        let child = |parent: &Commit, path: &[&str], data: &[u8], timestamp| {
+            let tree = parent
+                .tree(fs)
+                .unwrap()
+                .write(fs, path, data.to_vec())
+                .unwrap();
+            Commit::builder(&[parent.clone()], &tree)
+                .timestamp(timestamp)
+                .build(fs)
+                .unwrap()
+        };
+        let a = child(&root, &["x"], b"1", 1);
+        let b = child(&a, &["y"], b"2", 2);
+        let c = child(&a, &["x"], b"3", 3);
+        let tree = Tree::merge(
+            fs,
+            &a.tree(fs).unwrap(),
+            &b.tree(fs).unwrap(),
+            &c.tree(fs).unwrap(),
+        )
+        .unwrap()
+        .tree()
+        .unwrap();
+        let m = Commit::builder(&[b.clone(), c.clone()], &tree)
+            .timestamp(4)
+            .build(fs)
+            .unwrap();
+
+        let mut commits = HashMap::new();
+        commits.insert("root", root);
+        commits.insert("a", a);
+        commits.insert("b", b);
+        commits.insert("c", c);
+        commits.insert("m", m);
+        commits
+    }
+
+    /// Convert the results of an iterator to commit names
+    fn names<I: Iterator<Item = Fallible<Commit>>>(
+        fs: &FileSystem,
+        commits: &HashMap<&'static str, Commit>,
+        ancestors: I,
+    ) -> Vec<&'static str> {
+        let by_hash: HashMap<Hash, &'static str> = commits
+            .iter()
+            .map(|(n, c)| (c.hash(fs).unwrap().clone(), *n))
+            .collect();
+        ancestors
+            .map(|c| by_hash[c.unwrap().hash(fs).unwrap()])
+            .collect()
+    }
+
+    #[test]
+    fn topological() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let commits = history(&fs);
+        let m = Commit::for_hash(commits["m"].hash(&fs).unwrap());
+        assert_eq!(
+            names(&fs, &commits, m.ancestors(&fs)),
+            vec!["m", "b", "c", "a", "root"]
+        );
+        assert_eq!(
+            names(&fs, &commits, commits["b"].ancestors(&fs)),
+            vec!["b", "a", "root"]
+        );
+    }
+
+    #[test]
+    fn date() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let commits = history(&fs);
+        let ancestors = commits["m"].ancestors(&fs).order(WalkOrder::Date);
+        assert_eq!(
+            names(&fs, &commits, ancestors),
+            vec!["m", "c", "b", "a", "root"]
+        );
+    }
+
+    #[test]
+    fn max_depth() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let commits = history(&fs);
+        for order in &[WalkOrder::Topological, WalkOrder::Date] {
+            let ancestors = commits["m"].ancestors(&fs).order(*order);
+            assert_eq!(names(&fs, &commits, ancestors.max_depth(0)), vec!["m"]);
+            let ancestors = commits["m"].ancestors(&fs).order(*order);
+            assert_eq!(
+                names(&fs, &commits, ancestors.max_depth(2)).len(),
+                4,
+                "{:?}",
+                order
+            );
+        }
+    }
+
+    #[test]
+    fn max_depth_topological() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let mut commits = history(&fs);
+        // merge a with its own child b, so that a is both a parent of the merge and a parent of b,
+        // which is at the depth limit
+        let tree = commits["b"].tree(&fs).unwrap();
+        let parents = [commits["a"].clone(), commits["b"].clone()];
+        let m2 = Commit::make_merge(&fs, &parents, &tree).unwrap();
+        commits.insert("m2", m2.clone());
+
+        let ancestors = m2.ancestors(&fs).max_depth(1);
+        assert_eq!(names(&fs, &commits, ancestors), vec!["m2", "b", "a"]);
+    }
+
+    #[test]
+    fn path() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let commits = history(&fs);
+        let ancestors = commits["m"].ancestors(&fs).path(&["x"]);
+        assert_eq!(names(&fs, &commits, ancestors), vec!["c", "a"]);
+        let ancestors = commits["m"].ancestors(&fs).path(&["y"]);
+        assert_eq!(names(&fs, &commits, ancestors), vec!["b"]);
+        let ancestors = commits["m"].ancestors(&fs).path(&["z"]);
+        assert_eq!(names(&fs, &commits, ancestors), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn is_ancestor() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let commits = history(&fs);
+        let is_ancestor = |a, b| Commit::is_ancestor(&fs, &commits[a], &commits[b]).unwrap();
+        assert!(is_ancestor("a", "m"));
+        assert!(is_ancestor("root", "c"));
+        assert!(is_ancestor("m", "m"));
+        assert!(!is_ancestor("m", "a"));
+        assert!(!is_ancestor("b", "c"));
+    }
+
+    #[test]
+    fn unknown_metadata_version() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let commits = history(&fs);
+
+        // a commit with metadata from a later version does not prevent walking past it
+        let content = Content::AnnotatedCommit {
+            parents: vec![commits["a"].hash(&fs).unwrap().clone()],
+            tree: commits["a"].tree(&fs).unwrap().hash(&fs).unwrap().clone(),
+            version: 2,
+            metadata: vec![],
+        };
+        let future = Commit::for_hash(&content.store_in(&fs).unwrap());
+        let tree = future.tree(&fs).unwrap();
+        let child = future.make_child(&fs, &tree).unwrap();
+
+        assert!(Commit::is_ancestor(&fs, &commits["root"], &child).unwrap());
+        let ancestors = child.ancestors(&fs).order(WalkOrder::Date);
+        assert_eq!(ancestors.map(|c| c.unwrap()).count(), 4);
+    }
+
+    #[test]
+    fn merge_base() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let commits = history(&fs);
+        let merge_base = |a, b| {
+            let bases = Commit::merge_base(&fs, &commits[a], &commits[b]).unwrap();
+            names(&fs, &commits, bases.into_iter().map(Ok))
+        };
+        assert_eq!(merge_base("b", "c"), vec!["a"]);
+        assert_eq!(merge_base("c", "b"), vec!["a"]);
+        assert_eq!(merge_base("m", "c"), vec!["c"]);
+        assert_eq!(merge_base("a", "m"), vec!["a"]);
+        assert_eq!(merge_base("root", "root"), vec!["root"]);
+    }
+
+    #[test]
+    fn merge_base_criss_cross() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let mut commits = history(&fs);
+        // merge b and c again, in the other order, so that both are best common ancestors of the
+        // two merges
+        let tree = commits["m"].tree(&fs).unwrap();
+        let parents = [commits["c"].clone(), commits["b"].clone()];
+        let m2 = Commit::make_merge(&fs, &parents, &tree).unwrap();
+        commits.insert("m2", m2);
+
+        let bases = Commit::merge_base(&fs, &commits["m"], &commits["m2"]).unwrap();
+        let mut bases = names(&fs, &commits, bases.into_iter().map(Ok));
+        bases.sort();
+        assert_eq!(bases, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn missing_commit() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+        let mut ancestors = Commit::for_hash(&Hash::from_hex("0123")).ancestors(&fs);
+        assert!(ancestors.next().unwrap().is_err());
+        assert!(ancestors.next().is_none());
+    }
+}
@@ -0,0 +1,272 @@
+use super::fs::FileSystem;
+use super::tree::Tree;
+use crate::cas::Hash;
+use failure::Fallible;
+use std::collections::{BTreeSet, HashMap};
+
+/// A Conflict is a path at which both sides of a merge changed the data differently.  The data
+/// from the base and each side is included, with None where there was no data at that path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub path: Vec<String>,
+    pub base: Option<Vec<u8>>,
+    pub ours: Option<Vec<u8>>,
+    pub theirs: Option<Vec<u8>>,
+}
+
+/// The outcome of `Tree::merge`
+#[derive(Debug)]
+pub enum Merge {
+    /// The trees merged cleanly into this tree
+    Clean(Tree),
+
+    /// The trees could not be merged, due to these conflicts
+    Conflicted(Vec<Conflict>),
+}
+
+impl Merge {
+    /// Get the merged tree, if the merge was clean
+    pub fn tree(self) -> Option<Tree> {
+        match self {
+            Merge::Clean(tree) => Some(tree),
+            Merge::Conflicted(_) => None,
+        }
+    }
+}
+
+impl Tree {
+    /// Perform a three-way merge of `ours` and `theirs`, which both descend from `base`.  A change
+    /// made on only one side is taken from that side, and identical changes on both sides are
+    /// taken once.  If both sides changed the data at the same path differently, that path is a
+    /// conflict; a path deleted on one side and modified on the other is also a conflict.  Trees
+    /// left with no data and no children are removed, as with `Tree::remove`.
+    ///
+    /// Subtrees with equal hashes on two sides are resolved without loading them, so the cost of
+    /// the merge is proportional to the size of the changes, not of the trees.  The merged tree
+    /// shares unchanged subtrees with the inputs.
+    pub fn merge(fs: &FileSystem, base: &Tree, ours: &Tree, theirs: &Tree) -> Fallible<Merge> {
+        let mut merger = Merger {
+            fs,
+            path: vec![],
+            conflicts: vec![],
+        };
+        let merged = merger.merge(
+            Some(base.hash(fs)?),
+            Some(ours.hash(fs)?),
+            Some(theirs.hash(fs)?),
+        )?;
+
+        if !merger.conflicts.is_empty() {
+            return Ok(Merge::Conflicted(merger.conflicts));
+        }
+        Ok(Merge::Clean(match merged {
+            Some(hash) => Tree::for_hash(&hash),
+            None => Tree::empty(),
+        }))
+    }
+}
+
+struct Merger<'a> {
+    fs: &'a FileSystem,
+
+    /// The path to the subtrees currently being merged
+    path: Vec<String>,
+
+    conflicts: Vec<Conflict>,
+}
+
+impl<'a> Merger<'a> {
+    /// Merge the subtrees at the current path, returning the hash of the merged subtree or None
+    /// if it is empty.  A None input means there is no subtree at this path on that side.
+    fn merge(
+        &mut self,
+        base: Option<&Hash>,
+        ours: Option<&Hash>,
+        theirs: Option<&Hash>,
+    ) -> Fallible<Option<Hash>> {
+        if ours == theirs || base == theirs {
+            return Ok(ours.cloned());
+        }
+        if base == ours {
+            return Ok(theirs.cloned());
+        }
+
+        let (base_data, base_children) = Tree::content_for_hash(self.fs, base)?;
+        let (our_data, our_children) = Tree::content_for_hash(self.fs, ours)?;
+        let (their_data, their_children) = Tree::content_for_hash(self.fs, theirs)?;
+
+        let data = if our_data == their_data || base_data == their_data {
+            our_data
+        } else if base_data == our_data {
+            their_data
+        } else {
+            let fs = self.fs;
+            self.conflicts.push(Conflict {
+                path: self.path.clone(),
+                base: base_data.map(|d| d.load(fs)).transpose()?,
+                ours: our_data.map(|d| d.load(fs)).transpose()?,
+                theirs: their_data.map(|d| d.load(fs)).transpose()?,
+            });
+            None
+        };
+
+        // sort names so that conflicts are reported in a consistent order
+        let names: BTreeSet<&String> = base_children
+            .keys()
+            .chain(our_children.keys())
+            .chain(their_children.keys())
+            .collect();
+        let mut children = HashMap::new();
+        for name in names {
+            self.path.push(name.clone());
+            let merged = self.merge(
+                base_children.get(name),
+                our_children.get(name),
+                their_children.get(name),
+            )?;
+            self.path.pop();
+            if let Some(hash) = merged {
+                children.insert(name.clone(), hash);
+            }
+        }
+
+        if data.is_none() && children.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Tree::make(data, children).hash(self.fs)?.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cas::LocalStorage;
+    use crate::fs::tree::test::{retain_only, tree};
+
+    #[test]
+    fn clean() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+
+        let base = tree(&fs, &[(&["a", "x"], b"1"), (&["b"], b"2"), (&["c"], b"3")]);
+        let ours = base
+            .write(&fs, &["a", "y"], b"4".to_vec())
+            .unwrap()
+            .remove(&fs, &["c"])
+            .unwrap();
+        let theirs = base
+            .write(&fs, &["b"], b"5".to_vec())
+            .unwrap()
+            .write(&fs, &["a", "y"], b"4".to_vec())
+            .unwrap();
+
+        let merged = Tree::merge(&fs, &base, &ours, &theirs)
+            .unwrap()
+            .tree()
+            .unwrap();
+        let expected = tree(
+            &fs,
+            &[(&["a", "x"], b"1"), (&["a", "y"], b"4"), (&["b"], b"5")],
+        );
+        assert_eq!(merged.hash(&fs).unwrap(), expected.hash(&fs).unwrap());
+    }
+
+    #[test]
+    fn delete_everything() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+
+        let base = tree(&fs, &[(&["a"], b"1"), (&["b"], b"2")]);
+        let ours = base.clone().remove(&fs, &["a"]).unwrap();
+        let theirs = base.clone().remove(&fs, &["b"]).unwrap();
+
+        let merged = Tree::merge(&fs, &base, &ours, &theirs)
+            .unwrap()
+            .tree()
+            .unwrap();
+        assert_eq!(merged.hash(&fs).unwrap(), Tree::empty().hash(&fs).unwrap());
+    }
+
+    #[test]
+    fn unchanged_subtrees_not_loaded() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+
+        let base = tree(&fs, &[(&["big", "x"], b"1"), (&["small"], b"2")]);
+        let ours = base.write(&fs, &["small"], b"3".to_vec()).unwrap();
+        let theirs = base.write(&fs, &["other"], b"4".to_vec()).unwrap();
+        let base = Tree::for_hash(base.hash(&fs).unwrap());
+        let ours = Tree::for_hash(ours.hash(&fs).unwrap());
+        let theirs = Tree::for_hash(theirs.hash(&fs).unwrap());
+
+        // remove the "big" subtree from storage; it is the same on all sides, so the merge
+        // should not need it
+        let big = base.child(&fs, "big").unwrap().unwrap();
+        let mut keep = vec![];
+        for tree in &[&base, &ours, &theirs] {
+            keep.push(tree.hash(&fs).unwrap().clone());
+            for child in tree.children(&fs).unwrap().values() {
+                if child.hash(&fs).unwrap() != big.hash(&fs).unwrap() {
+                    keep.push(child.hash(&fs).unwrap().clone());
+                }
+            }
+        }
+        retain_only(&fs, &keep);
+        assert!(big.children(&fs).is_err());
+
+        let merged = Tree::merge(&fs, &base, &ours, &theirs)
+            .unwrap()
+            .tree()
+            .unwrap();
+        assert_eq!(
+            merged
+                .child(&fs, "big")
+                .unwrap()
+                .unwrap()
+                .hash(&fs)
+                .unwrap(),
+            big.hash(&fs).unwrap()
+        );
+        assert_eq!(merged.read(&fs, &["small"]).unwrap(), Some(b"3".to_vec()));
+        assert_eq!(merged.read(&fs, &["other"]).unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn conflicts() {
+        let fs = FileSystem::new(Box::new(LocalStorage::new()));
+
+        let base = tree(&fs, &[(&["a"], b"1"), (&["b", "c"], b"2"), (&["d"], b"3")]);
+        let ours = base
+            .write(&fs, &["a"], b"ours".to_vec())
+            .unwrap()
+            .remove(&fs, &["b", "c"])
+            .unwrap()
+            .write(&fs, &["d"], b"same".to_vec())
+            .unwrap();
+        let theirs = base
+            .write(&fs, &["a"], b"theirs".to_vec())
+            .unwrap()
+            .write(&fs, &["b", "c"], b"modified".to_vec())
+            .unwrap()
+            .write(&fs, &["d"], b"same".to_vec())
+            .unwrap();
+
+        match Tree::merge(&fs, &base, &ours, &theirs).unwrap() {
+            Merge::Clean(tree) => panic!("unexpected clean merge: {:?}", tree),
+            Merge::Conflicted(conflicts) => assert_eq!(
+                conflicts,
+                vec![
+                    Conflict {
+                        path: vec!["a".to_string()],
+                        base: Some(b"1".to_vec()),
+                        ours: Some(b"ours".to_vec()),
+                        theirs: Some(b"theirs".to_vec()),
+                    },
+                    Conflict {
+                        path: vec!["b".to_string(), "c".to_string()],
+                        base: Some(b"2".to_vec()),
+                        ours: None,
+                        theirs: Some(b"modified".to_vec()),
+                    },
+                ]
+            ),
+        }
+    }
+}
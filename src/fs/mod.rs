@@ -29,10 +29,16 @@
 //! assert_eq!(tree.read(&fs, &["b"]).unwrap(), Some(vec![2, 2]));
 //! ```
 
+mod chunk;
 mod commit;
 mod content;
+mod diff;
 mod fs;
+mod fsck;
+mod gc;
+mod history;
 mod lazy;
+mod merge;
 mod tree;
 
 #[cfg(test)]
@@ -41,6 +47,10 @@ mod hashes;
 mod error;
 pub use self::error::*;
 
-pub use self::commit::Commit;
+pub use self::commit::{Commit, CommitBuilder, Metadata};
+pub use self::diff::Change;
 pub use self::fs::FileSystem;
+pub use self::fsck::{ObjectKind, Problem};
+pub use self::history::{Ancestors, WalkOrder};
+pub use self::merge::{Conflict, Merge};
 pub use self::tree::Tree;
@@ -1,8 +1,9 @@
+use super::chunk::{load_chunked, store_chunked, CHUNK_THRESHOLD};
 use super::content::Content;
 use super::fs::FileSystem;
 use super::lazy::LazyHashedObject;
 use crate::cas::Hash;
-use failure::Fallible;
+use failure::{bail, Fallible};
 use std::collections::HashMap;
 use std::fmt::{Debug, Error as FmtError, Formatter};
 use std::rc::Rc;
@@ -17,6 +18,33 @@ pub struct Tree {
     inner: Rc<LazyHashedObject<Content>>,
 }
 
+/// The data at a tree node, either stored inline in the node or, for large values, in a separate
+/// manifest of chunks.
+#[derive(Clone, PartialEq)]
+pub(crate) enum Data {
+    Inline(Vec<u8>),
+    Chunked(Hash),
+}
+
+impl Data {
+    /// Prepare data for storage in a tree node, storing it as chunks if it is large
+    fn new(fs: &FileSystem, data: Vec<u8>) -> Fallible<Data> {
+        if data.len() > CHUNK_THRESHOLD {
+            Ok(Data::Chunked(store_chunked(fs, &data)?))
+        } else {
+            Ok(Data::Inline(data))
+        }
+    }
+
+    /// Load the data, reassembling it from its chunks if necessary
+    pub(crate) fn load(self, fs: &FileSystem) -> Fallible<Vec<u8>> {
+        match self {
+            Data::Inline(data) => Ok(data),
+            Data::Chunked(manifest) => load_chunked(fs, &manifest),
+        }
+    }
+}
+
 impl Tree {
     /// Create a new, empty tree
     pub fn empty() -> Tree {
@@ -40,24 +68,70 @@ impl Tree {
         }
     }
 
+    /// return a Tree with the given data and children
+    pub(crate) fn make(data: Option<Data>, children: HashMap<String, Hash>) -> Tree {
+        Tree::for_content(match data {
+            None => Content::Tree {
+                data: None,
+                children,
+            },
+            Some(Data::Inline(data)) => Content::Tree {
+                data: Some(data),
+                children,
+            },
+            Some(Data::Chunked(manifest)) => Content::ChunkedTree { manifest, children },
+        })
+    }
+
     /// Get the hash for this tree
     pub fn hash(&self, fs: &FileSystem) -> Fallible<&Hash> {
         self.inner.hash(fs)
     }
 
-    /// Utility function to get the content or panic trying
-    fn content(&self, fs: &FileSystem) -> Fallible<(&Option<Vec<u8>>, &HashMap<String, Hash>)> {
+    /// Utility function to get the content, failing if this is not a tree
+    pub(crate) fn content(
+        &self,
+        fs: &FileSystem,
+    ) -> Fallible<(Option<Data>, &HashMap<String, Hash>)> {
         let content = self.inner.content(fs)?;
-        if let Content::Tree { data, children } = content {
-            Ok((data, children))
-        } else {
-            panic!("{:?} is not a tree", self.inner.hash(fs).unwrap());
+        match content {
+            Content::Tree { data, children } => Ok((data.clone().map(Data::Inline), children)),
+            Content::ChunkedTree { manifest, children } => {
+                Ok((Some(Data::Chunked(manifest.clone())), children))
+            }
+            _ => bail!("{:?} is not a tree", self.inner.hash(fs)?),
+        }
+    }
+
+    /// Utility function to get the content of the tree with the given hash, treating a missing
+    /// tree (None) as empty.  This copies the children, so is suited to walking several trees in
+    /// parallel, as for diffs and merges.
+    pub(crate) fn content_for_hash(
+        fs: &FileSystem,
+        hash: Option<&Hash>,
+    ) -> Fallible<(Option<Data>, HashMap<String, Hash>)> {
+        match hash {
+            Some(hash) => {
+                let tree = Tree::for_hash(hash);
+                let (data, children) = tree.content(fs)?;
+                Ok((data, children.clone()))
+            }
+            None => Ok((None, HashMap::new())),
+        }
+    }
+
+    /// Utility function to get the children hashes, without copying the data
+    pub(crate) fn child_hashes(&self, fs: &FileSystem) -> Fallible<&HashMap<String, Hash>> {
+        let content = self.inner.content(fs)?;
+        match content {
+            Content::Tree { children, .. } | Content::ChunkedTree { children, .. } => Ok(children),
+            _ => bail!("{:?} is not a tree", self.inner.hash(fs)?),
         }
     }
 
     /// Get the children of this tree.
     pub fn children(&self, fs: &FileSystem) -> Fallible<HashMap<String, Tree>> {
-        let (_, children) = self.content(fs)?;
+        let children = self.child_hashes(fs)?;
         Ok(children
             .iter()
             .map(|(n, h)| (n.clone(), Tree::for_hash(h)))
@@ -66,7 +140,7 @@ impl Tree {
 
     /// Get a child of this tree, if it exists
     pub fn child(&self, fs: &FileSystem, name: &str) -> Fallible<Option<Tree>> {
-        let (_, children) = self.content(fs)?;
+        let children = self.child_hashes(fs)?;
         if let Some(h) = children.get(name) {
             Ok(Some(Tree::for_hash(h)))
         } else {
@@ -76,15 +150,17 @@ impl Tree {
 
     // TODO: iter_children
 
-    /// Get the data at this tree.
+    /// Get the data at this tree.  Large values are reassembled from their chunks.
     pub fn data(&self, fs: &FileSystem) -> Fallible<Option<Vec<u8>>> {
         let (data, _) = self.content(fs)?;
-        Ok(data.clone())
+        data.map(|data| data.load(fs)).transpose()
     }
 
     /// Return a tree containing new value at the designated path, replacing any
     /// existing value at that path.  The storage is used to read any unresolved
-    /// tree nodes, but nothing is written to storage.
+    /// tree nodes, but nothing is written to storage, except that values larger than
+    /// CHUNK_THRESHOLD are split into chunks which are stored immediately.  Chunks are
+    /// content-defined, so similar large values share most of their chunks.
     ///
     /// Note that path elements and data can coexist, unlike a UNIX filesystem; that is, writing a
     /// value to "usr/bin" will not invalidate paths like "usr/bin/rustc".
@@ -141,18 +217,13 @@ impl Tree {
 
         if let Some(newdata) = newdata {
             // we are adding data, so write that data in subtree
+            let newdata = Data::new(fs, newdata)?;
             let subtree = trees.pop().unwrap();
             let mut subtree = if let Some(ref st) = subtree {
-                let (_, children) = st.content(fs)?;
-                Tree::for_content(Content::Tree {
-                    data: Some(newdata),
-                    children: children.clone(),
-                })
+                let children = st.child_hashes(fs)?;
+                Tree::make(Some(newdata), children.clone())
             } else {
-                Tree::for_content(Content::Tree {
-                    data: Some(newdata),
-                    children: HashMap::new(),
-                })
+                Tree::make(Some(newdata), HashMap::new())
             };
 
             // then work backward, updating trees along the way
@@ -160,17 +231,14 @@ impl Tree {
                 if let Some(t) = tree.take() {
                     // create a clone of t with subtree as a child
                     let (data, children) = t.content(fs)?;
-                    let (data, mut children) = (data.clone(), children.clone());
+                    let mut children = children.clone();
                     children.insert(elt.to_string(), subtree.hash(fs)?.clone());
-                    subtree = Tree::for_content(Content::Tree { data, children });
+                    subtree = Tree::make(data, children);
                 } else {
                     // create a new tree with subtree as child
                     let mut children = HashMap::new();
                     children.insert(elt.to_string(), subtree.hash(fs)?.clone());
-                    subtree = Tree::for_content(Content::Tree {
-                        data: None,
-                        children,
-                    });
+                    subtree = Tree::make(None, children);
                 }
             }
 
@@ -179,12 +247,9 @@ impl Tree {
             // newdata is None so we are deleting data; start by deleting the data from the leaf
             let mut subtree = trees.pop().unwrap();
             if let Some(ref st) = subtree {
-                let (_, children) = st.content(fs)?;
+                let children = st.child_hashes(fs)?;
                 if children.len() > 0 {
-                    subtree = Some(Tree::for_content(Content::Tree {
-                        data: None,
-                        children: children.clone(),
-                    }))
+                    subtree = Some(Tree::make(None, children.clone()))
                 } else {
                     // this leaf node is now empty, so drop it
                     subtree = None
@@ -201,18 +266,15 @@ impl Tree {
                     (Some(st), Some(t)) => {
                         // create a clone of t with st as a child
                         let (data, children) = t.content(fs)?;
-                        let (data, mut children) = (data.clone(), children.clone());
+                        let mut children = children.clone();
                         children.insert(elt.to_string(), st.hash(fs)?.clone());
-                        subtree = Some(Tree::for_content(Content::Tree { data, children }));
+                        subtree = Some(Tree::make(data, children));
                     }
                     (Some(st), None) => {
                         // create a new tree with st as child
                         let mut children = HashMap::new();
                         children.insert(elt.to_string(), st.hash(fs)?.clone());
-                        subtree = Some(Tree::for_content(Content::Tree {
-                            data: None,
-                            children,
-                        }));
+                        subtree = Some(Tree::make(None, children));
                     }
                     (None, Some(t)) => {
                         // create a clone of t with elt removed, or None if t only contains elt
@@ -220,9 +282,9 @@ impl Tree {
                         if children.len() == 1 && children.keys().next().unwrap() == elt {
                             subtree = None;
                         } else {
-                            let (data, mut children) = (data.clone(), children.clone());
+                            let mut children = children.clone();
                             children.remove(&elt[..]);
-                            subtree = Some(Tree::for_content(Content::Tree { data, children }));
+                            subtree = Some(Tree::make(data, children));
                         }
                     }
                     (None, None) => {
@@ -247,29 +309,57 @@ impl Debug for Tree {
             write!(f, "@{:?}", h)?;
         }
         if let Some(c) = self.inner.maybe_content() {
-            if let Content::Tree { data, children } = c {
-                write!(f, " [{:?}", data)?;
-                let mut names: Vec<&String> = children.keys().collect();
-                names.sort();
-                for name in names.drain(..) {
-                    write!(f, ", {}: {:?}", name, children.get(name).unwrap())?;
+            let children = match c {
+                Content::Tree { data, children } => {
+                    write!(f, " [{:?}", data)?;
+                    children
                 }
-                write!(f, "]")?;
-            } else {
-                write!(f, "(not a tree!)")?;
+                Content::ChunkedTree { manifest, children } => {
+                    write!(f, " [chunked@{:?}", manifest)?;
+                    children
+                }
+                _ => return write!(f, "(not a tree!)"),
+            };
+            let mut names: Vec<&String> = children.keys().collect();
+            names.sort();
+            for name in names.drain(..) {
+                write!(f, ", {}: {:?}", name, children.get(name).unwrap())?;
             }
+            write!(f, "]")?;
         }
         Ok(())
     }
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::FileSystem;
     use super::*;
     use crate::cas::Hash;
     use crate::cas::LocalStorage;
+    use crate::fs::chunk::test::random_data;
     use crate::fs::hashes::EMPTY_TREE_HASH;
+    use crate::fs::lazy::LazyContent;
+
+    /// Build a tree containing the given data at the given paths
+    pub(crate) fn tree(fs: &FileSystem, values: &[(&[&str], &[u8])]) -> Tree {
+        let mut tree = Tree::empty();
+        for (path, data) in values {
+            tree = tree.write(fs, path, data.to_vec()).unwrap();
+        }
+        tree
+    }
+
+    /// Collect garbage, retaining only the objects with the given hashes, so that a test can
+    /// show that the other objects are never loaded
+    pub(crate) fn retain_only(fs: &FileSystem, keep: &[Hash]) {
+        let storage = &fs.storage;
+        storage.begin_gc().unwrap();
+        for hash in keep {
+            storage.touch(hash).unwrap();
+        }
+        storage.end_gc();
+    }
 
     #[test]
     fn test_empty() {
@@ -395,6 +485,62 @@ mod test {
         );
     }
 
+    #[test]
+    fn large_value_chunked() {
+        let storage = LocalStorage::new();
+        let fs = FileSystem::new(Box::new(storage));
+
+        let data = random_data(1, 200 * 1024);
+        let tree = make_test_tree(&fs)
+            .write(&fs, &["sub", "big"], data.clone())
+            .unwrap();
+        // reload from storage and read it back
+        let tree = Tree::for_hash(tree.hash(&fs).unwrap());
+        assert_eq!(tree.read(&fs, &["sub", "big"]).unwrap(), Some(data));
+        assert_eq!(tree.read(&fs, &["sub", "one"]).unwrap(), Some(vec![1]));
+
+        // the value was stored as a chunked tree, not inline
+        let big = tree.child(&fs, "sub").unwrap().unwrap();
+        let big = big.child(&fs, "big").unwrap().unwrap();
+        big.data(&fs).unwrap();
+        assert!(format!("{:?}", big).contains("chunked@"));
+    }
+
+    #[test]
+    fn large_values_share_chunks() {
+        let storage = LocalStorage::new();
+        let fs = FileSystem::new(Box::new(storage));
+
+        let data = random_data(2, 1024 * 1024);
+        let mut modified = data.clone();
+        modified[300_000] ^= 0xff;
+
+        let tree = Tree::empty().write(&fs, &["a"], data).unwrap();
+        let manifest_chunks = |tree: &Tree| {
+            let (data, _) = tree.child(&fs, "a").unwrap().unwrap().content(&fs).unwrap();
+            match data {
+                Some(Data::Chunked(manifest)) => match Content::retrieve_from(&fs, &manifest) {
+                    Ok(Content::Manifest { chunks }) => chunks,
+                    _ => panic!("bad manifest"),
+                },
+                _ => panic!("not chunked"),
+            }
+        };
+        let chunks1 = manifest_chunks(&tree);
+        let tree = tree.write(&fs, &["a"], modified.clone()).unwrap();
+        let chunks2 = manifest_chunks(&tree);
+
+        // only the chunk containing the modification differs
+        assert_eq!(chunks1.len(), chunks2.len());
+        let differing = chunks1
+            .iter()
+            .zip(chunks2.iter())
+            .filter(|(a, b)| a != b)
+            .count();
+        assert_eq!(differing, 1);
+        assert_eq!(tree.read(&fs, &["a"]).unwrap(), Some(modified));
+    }
+
     #[test]
     fn remove_nonexistent() {
         let storage = LocalStorage::new();
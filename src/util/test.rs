@@ -1,4 +1,9 @@
 use env_logger;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Once;
 
 static LOGGER_INITIALIZED: Once = Once::new();
@@ -8,3 +13,28 @@ static LOGGER_INITIALIZED: Once = Once::new();
 pub fn init_env_logger() {
     LOGGER_INITIALIZED.call_once(|| env_logger::init());
 }
+
+/// A temporary directory, removed (along with its contents) when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create a new, empty temporary directory with a name unique to this process and call.
+    pub fn new() -> TempDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("rubbish-test-{}-{}", process::id(), n));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    /// Get the path of this directory
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}